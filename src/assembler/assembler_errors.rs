@@ -1,41 +1,441 @@
 use std::error::Error;
 use std::fmt;
+use std::io;
+
+use nom::types::CompleteStr;
+use nom::{Context, ErrorKind};
+
+use crate::assembler::macros::MACRO_DEPTH_LIMIT;
+use crate::assembler::operand_parsers::{
+    INVALID_ESCAPE, INVALID_INTEGER_LITERAL, UNTERMINATED_STRING,
+};
+use crate::assembler::span::Span;
+use crate::instruction::{Opcode, OperandKind};
 
 #[derive(Debug, Clone)]
 pub enum AssemblerError {
-    NoSegmentDeclarationFound { instruction: u32 },
-    StringConstantDeclaredWithoutLabel { instruction: u32 },
-    SymbolAlreadyDeclared,
-    UnknownDirectiveFound { directive: String },
-    NonOpcodeInOpcodeField,
-    InsufficientSections,
-    ParseError { error: String },
+    NoSegmentDeclarationFound {
+        instruction: u32,
+        span: Span,
+    },
+    StringConstantDeclaredWithoutLabel {
+        instruction: u32,
+        span: Span,
+    },
+    SymbolAlreadyDeclared {
+        name: String,
+        span: Span,
+        original: Box<Span>,
+    },
+    UnknownDirectiveFound {
+        directive: String,
+        span: Span,
+    },
+    InvalidDirectiveOperands {
+        directive: String,
+        expected: &'static str,
+        span: Span,
+    },
+    IncludeFailed {
+        path: String,
+        reason: io::ErrorKind,
+        span: Span,
+    },
+    IncludeCycle {
+        path: String,
+        span: Span,
+    },
+    InvalidMacroDefinition {
+        reason: &'static str,
+        span: Span,
+    },
+    UnterminatedMacro {
+        name: String,
+        span: Span,
+    },
+    MacroArgumentMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    MacroRecursionLimit {
+        name: String,
+        span: Span,
+    },
+    NonOpcodeInOpcodeField {
+        span: Span,
+    },
+    UnknownOpcode {
+        name: String,
+        suggestion: Option<String>,
+        span: Span,
+    },
+    InvalidOperand {
+        operand: String,
+        span: Span,
+    },
+    OperandMismatch {
+        opcode: Opcode,
+        expected: &'static [OperandKind],
+        found: String,
+        span: Span,
+    },
+    InvalidRegister {
+        register: u8,
+        span: Span,
+    },
+    UndefinedSymbol {
+        name: String,
+        suggestion: Option<String>,
+        span: Span,
+    },
+    InsufficientSections {
+        span: Span,
+    },
+    ParseError {
+        error: String,
+        span: Span,
+    },
+    LiteralOutOfRange {
+        value: i32,
+        span: Span,
+    },
+    InvalidIntegerLiteral {
+        literal: String,
+        span: Span,
+    },
+    InvalidEscape {
+        escape: String,
+        span: Span,
+    },
+    UnterminatedString {
+        span: Span,
+    },
+    JumpTargetOutOfRange {
+        instruction: u32,
+        label: String,
+        span: Span,
+    },
+    LabelOutOfRange {
+        name: String,
+        value: u32,
+        span: Span,
+    },
+}
+
+impl AssemblerError {
+    /// The source the error points at
+    pub fn span(&self) -> &Span {
+        match self {
+            AssemblerError::NoSegmentDeclarationFound { span, .. }
+            | AssemblerError::StringConstantDeclaredWithoutLabel { span, .. }
+            | AssemblerError::SymbolAlreadyDeclared { span, .. }
+            | AssemblerError::UnknownDirectiveFound { span, .. }
+            | AssemblerError::InvalidDirectiveOperands { span, .. }
+            | AssemblerError::IncludeFailed { span, .. }
+            | AssemblerError::IncludeCycle { span, .. }
+            | AssemblerError::InvalidMacroDefinition { span, .. }
+            | AssemblerError::UnterminatedMacro { span, .. }
+            | AssemblerError::MacroArgumentMismatch { span, .. }
+            | AssemblerError::MacroRecursionLimit { span, .. }
+            | AssemblerError::NonOpcodeInOpcodeField { span }
+            | AssemblerError::UnknownOpcode { span, .. }
+            | AssemblerError::InvalidOperand { span, .. }
+            | AssemblerError::OperandMismatch { span, .. }
+            | AssemblerError::InvalidRegister { span, .. }
+            | AssemblerError::UndefinedSymbol { span, .. }
+            | AssemblerError::InsufficientSections { span }
+            | AssemblerError::ParseError { span, .. }
+            | AssemblerError::LiteralOutOfRange { span, .. }
+            | AssemblerError::InvalidIntegerLiteral { span, .. }
+            | AssemblerError::InvalidEscape { span, .. }
+            | AssemblerError::UnterminatedString { span }
+            | AssemblerError::JumpTargetOutOfRange { span, .. }
+            | AssemblerError::LabelOutOfRange { span, .. } => span,
+        }
+    }
+
+    /// Mutable access to the span, e.g. to record a macro expansion
+    pub fn span_mut(&mut self) -> &mut Span {
+        match self {
+            AssemblerError::NoSegmentDeclarationFound { span, .. }
+            | AssemblerError::StringConstantDeclaredWithoutLabel { span, .. }
+            | AssemblerError::SymbolAlreadyDeclared { span, .. }
+            | AssemblerError::UnknownDirectiveFound { span, .. }
+            | AssemblerError::InvalidDirectiveOperands { span, .. }
+            | AssemblerError::IncludeFailed { span, .. }
+            | AssemblerError::IncludeCycle { span, .. }
+            | AssemblerError::InvalidMacroDefinition { span, .. }
+            | AssemblerError::UnterminatedMacro { span, .. }
+            | AssemblerError::MacroArgumentMismatch { span, .. }
+            | AssemblerError::MacroRecursionLimit { span, .. }
+            | AssemblerError::NonOpcodeInOpcodeField { span }
+            | AssemblerError::UnknownOpcode { span, .. }
+            | AssemblerError::InvalidOperand { span, .. }
+            | AssemblerError::OperandMismatch { span, .. }
+            | AssemblerError::InvalidRegister { span, .. }
+            | AssemblerError::UndefinedSymbol { span, .. }
+            | AssemblerError::InsufficientSections { span }
+            | AssemblerError::ParseError { span, .. }
+            | AssemblerError::LiteralOutOfRange { span, .. }
+            | AssemblerError::InvalidIntegerLiteral { span, .. }
+            | AssemblerError::InvalidEscape { span, .. }
+            | AssemblerError::UnterminatedString { span }
+            | AssemblerError::JumpTargetOutOfRange { span, .. }
+            | AssemblerError::LabelOutOfRange { span, .. } => span,
+        }
+    }
+
+    /// A suggestion for fixing the error
+    pub fn hint(&self) -> String {
+        let hint = match self {
+            AssemblerError::NoSegmentDeclarationFound { .. } => {
+                "start the program with .data or .code"
+            }
+            AssemblerError::StringConstantDeclaredWithoutLabel { .. } => {
+                "give the constant a label, e.g. `name: .asciiz 'text'`"
+            }
+            AssemblerError::SymbolAlreadyDeclared { .. } => "rename one of the labels",
+            AssemblerError::UnknownDirectiveFound { .. } => {
+                "the supported directives are .data, .code, .include, .macro, .asciiz, .byte, .half, .word, .integer, .space and .align"
+            }
+            AssemblerError::InvalidDirectiveOperands { .. } => {
+                "e.g. `.asciiz \"text\"`, `.word #1 #2`, `.space #16` or `.align #4`"
+            }
+            AssemblerError::IncludeFailed { .. } => {
+                "paths are relative to the including file or an include path (-I)"
+            }
+            AssemblerError::IncludeCycle { .. } => "a file cannot include itself, even indirectly",
+            AssemblerError::InvalidMacroDefinition { .. } => {
+                "macros are written `.macro name param ...`, the body, then `.endm`"
+            }
+            AssemblerError::UnterminatedMacro { .. } => "end the macro body with .endm",
+            AssemblerError::MacroArgumentMismatch { .. } => {
+                "pass one operand for each parameter, separated by spaces"
+            }
+            AssemblerError::MacroRecursionLimit { .. } => {
+                "check for a macro that invokes itself"
+            }
+            AssemblerError::NonOpcodeInOpcodeField { .. } => {
+                "instructions start with an opcode, e.g. `load $0 #1`"
+            }
+            AssemblerError::UnknownOpcode {
+                suggestion: Some(suggestion),
+                ..
+            } => return format!("did you mean `{}`?", suggestion),
+            AssemblerError::UnknownOpcode { .. } => {
+                "check the spelling, or define a macro with .macro"
+            }
+            AssemblerError::InvalidOperand { .. } => {
+                "operands are registers, integer literals or @labels"
+            }
+            AssemblerError::OperandMismatch { .. } => {
+                "check the operands the opcode takes and their order"
+            }
+            AssemblerError::InvalidRegister { .. } => "registers are numbered $0 to $31",
+            AssemblerError::InsufficientSections { .. } => {
+                "declare a .data section followed by a .code section"
+            }
+            AssemblerError::ParseError { .. } => {
+                "instructions are written `opcode operands`, directives `.name operands`"
+            }
+            AssemblerError::LiteralOutOfRange { .. } => {
+                "addresses and JMPF/JMPB distances take 0 to 65535, JNEQ/JZ displacements -32768 to 32767, LUI and .half -32768 to 65535, .byte -128 to 255 and heap offsets 0 to 255"
+            }
+            AssemblerError::InvalidIntegerLiteral { .. } => {
+                "integer literals look like #-1, #0xFF, #0b1010 or #'A' and fit in 32 bits"
+            }
+            AssemblerError::InvalidEscape { .. } => {
+                r#"the escapes are \n, \t, \\, \", \', \0, \x00 to \x7f and \u{...}"#
+            }
+            AssemblerError::UnterminatedString { .. } => {
+                r#"close the string with a " on the same line"#
+            }
+            AssemblerError::JumpTargetOutOfRange { .. } => {
+                "move the label closer, or jump through a register with `jmp`"
+            }
+            AssemblerError::LabelOutOfRange { .. } => {
+                "labels used as operands must lie within the first 64 KiB of the program"
+            }
+            AssemblerError::UndefinedSymbol {
+                suggestion: Some(suggestion),
+                ..
+            } => return format!("did you mean @{}?", suggestion),
+            AssemblerError::UndefinedSymbol { .. } => "declare it with a label, e.g. `name: hlt`",
+        };
+        hint.to_string()
+    }
+
+    /// Turns a failure to parse `source` into an error pointing at where
+    /// parsing stopped
+    pub fn from_parse_failure(
+        file: &str,
+        source: &str,
+        error: &nom::Err<CompleteStr>,
+    ) -> AssemblerError {
+        match error {
+            nom::Err::Failure(Context::Code(
+                position,
+                ErrorKind::Custom(INVALID_INTEGER_LITERAL),
+            )) => {
+                // The position is at the digits, which whitespace may separate
+                // from the `#`
+                let digits = source.len() - position.len();
+                let start = source[..digits].rfind('#').unwrap_or(digits);
+                let length = if let Some(quoted) = position.strip_prefix('\'') {
+                    quoted.find('\'').map_or(position.len(), |end| end + 2)
+                } else {
+                    position
+                        .find(|c: char| c.is_whitespace() || c == ';')
+                        .unwrap_or(position.len())
+                };
+                AssemblerError::InvalidIntegerLiteral {
+                    literal: source[start..digits + length].to_string(),
+                    span: Span::new(file, source, start, digits + length),
+                }
+            }
+            nom::Err::Failure(Context::Code(position, ErrorKind::Custom(INVALID_ESCAPE))) => {
+                let start = source.len() - position.len();
+                let length = match position[1..].chars().next() {
+                    Some('x') => 4,
+                    Some('u') => position.find('}').map_or(2, |end| end + 1),
+                    _ => 2,
+                };
+                let end = (start + length).min(source.len());
+                AssemblerError::InvalidEscape {
+                    escape: source[start..end].to_string(),
+                    span: Span::new(file, source, start, end),
+                }
+            }
+            nom::Err::Failure(Context::Code(position, ErrorKind::Custom(UNTERMINATED_STRING))) => {
+                let start = source.len() - position.len();
+                AssemblerError::UnterminatedString {
+                    span: Span::new(file, source, start, source.len()),
+                }
+            }
+            _ => {
+                let start = source.len() - source.trim_start().len();
+                AssemblerError::ParseError {
+                    error: error.to_string(),
+                    span: Span::new(file, source, start, source.len()),
+                }
+            }
+        }
+    }
+
+    /// Renders the error with the offending source line underlined, followed
+    /// by the hint and, for redeclared symbols, the original declaration
+    pub fn render(&self) -> String {
+        let mut rendered = format!("error: {}\n{}\n", self, self.span().render());
+        let gutter = " ".repeat(self.span().line.to_string().len());
+        rendered.push_str(&format!("{} = hint: {}\n", gutter, self.hint()));
+        rendered.push_str(&self.span().render_expansions());
+        if let AssemblerError::SymbolAlreadyDeclared {
+            ref name,
+            ref original,
+            ..
+        } = *self
+        {
+            rendered.push_str(&format!(
+                "note: {} was first declared here\n{}\n",
+                name,
+                original.render()
+            ));
+        }
+        rendered
+    }
+}
+
+/// Joins descriptions as "a, b and c", or "no operands" if there are none
+pub(crate) fn describe_list<'a>(items: impl Iterator<Item = &'a str>) -> String {
+    let items: Vec<&str> = items.collect();
+    match items.split_last() {
+        None => "no operands".to_string(),
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+    }
 }
 
 impl fmt::Display for AssemblerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-          AssemblerError::NoSegmentDeclarationFound{ instruction } => {
+          AssemblerError::NoSegmentDeclarationFound{ instruction, .. } => {
             f.write_str(&format!("No segment declaration (e.g., .code, .data) prior to finding an opcode or other directive. Instruction # was {}:", instruction))
           }
-          AssemblerError::StringConstantDeclaredWithoutLabel{ instruction } => {
+          AssemblerError::StringConstantDeclaredWithoutLabel{ instruction, .. } => {
             f.write_str(&format!("Found a string constant without a corresponding label. Instruction # was {}: ", instruction))
           }
-          AssemblerError::SymbolAlreadyDeclared => {
-            f.write_str("This symbol was previously declared.")
+          AssemblerError::SymbolAlreadyDeclared{ ref name, .. } => {
+            f.write_str(&format!("The symbol {} was previously declared.", name))
           }
-          AssemblerError::UnknownDirectiveFound { ref directive } => {
+          AssemblerError::UnknownDirectiveFound { ref directive, .. } => {
             f.write_str(&format!("Invalid or unknown directive found. Directive name was: {}", directive))
           }
-          AssemblerError::NonOpcodeInOpcodeField => {
+          AssemblerError::InvalidDirectiveOperands{ ref directive, expected, .. } => {
+            f.write_str(&format!("The directive .{} expects {}", directive, expected))
+          }
+          AssemblerError::IncludeFailed{ ref path, ref reason, .. } => {
+            f.write_str(&format!("Unable to include {}: {}", path, reason))
+          }
+          AssemblerError::IncludeCycle{ ref path, .. } => {
+            f.write_str(&format!("Include cycle: {} is already being included", path))
+          }
+          AssemblerError::InvalidMacroDefinition{ reason, .. } => {
+            f.write_str(&format!("Invalid macro definition: {}", reason))
+          }
+          AssemblerError::UnterminatedMacro{ ref name, .. } => {
+            f.write_str(&format!("The macro {} is never ended with .endm", name))
+          }
+          AssemblerError::MacroArgumentMismatch{ ref name, expected, found, .. } => {
+            f.write_str(&format!("The macro {} takes {} arguments, but was given {}", name, expected, found))
+          }
+          AssemblerError::MacroRecursionLimit{ ref name, .. } => {
+            f.write_str(&format!("Expanding the macro {} nests more than {} macros deep", name, MACRO_DEPTH_LIMIT))
+          }
+          AssemblerError::NonOpcodeInOpcodeField{ .. } => {
             f.write_str("An non-opcode was found in an opcode field")
           }
-          AssemblerError::InsufficientSections => {
+          AssemblerError::UnknownOpcode{ ref name, .. } => {
+            f.write_str(&format!("Unknown opcode or macro `{}`", name))
+          }
+          AssemblerError::InvalidOperand{ ref operand, .. } => {
+            f.write_str(&format!("Found {} where an operand was expected", operand))
+          }
+          AssemblerError::OperandMismatch{ opcode, expected, ref found, .. } => {
+            let expected = describe_list(expected.iter().map(|kind| kind.describe()));
+            f.write_str(&format!("{} expects {}, but found {}", opcode.mnemonic(), expected, found))
+          }
+          AssemblerError::InvalidRegister{ register, .. } => {
+            f.write_str(&format!("There is no register {}", register))
+          }
+          AssemblerError::UndefinedSymbol{ ref name, .. } => {
+            f.write_str(&format!("The symbol {} is used but never declared", name))
+          }
+          AssemblerError::InsufficientSections{ .. } => {
             f.write_str("Less than two sections/segments were found in the code")
           }
-          AssemblerError::ParseError{ ref error } => {
+          AssemblerError::ParseError{ ref error, .. } => {
             f.write_str(&format!("There was an error parsing the code: {}", error))
           }
+          AssemblerError::LiteralOutOfRange{ value, .. } => {
+            f.write_str(&format!("The literal {} does not fit in its operand field", value))
+          }
+          AssemblerError::InvalidIntegerLiteral{ ref literal, .. } => {
+            f.write_str(&format!("The integer literal {} is malformed or does not fit in 32 bits", literal))
+          }
+          AssemblerError::InvalidEscape{ ref escape, .. } => {
+            f.write_str(&format!("Invalid escape sequence {} in a string literal", escape))
+          }
+          AssemblerError::UnterminatedString{ .. } => {
+            f.write_str("A string literal is not terminated")
+          }
+          AssemblerError::JumpTargetOutOfRange{ instruction, ref label, .. } => {
+            f.write_str(&format!("The label {} cannot be reached by this relative jump. Instruction # was {}", label, instruction))
+          }
+          AssemblerError::LabelOutOfRange{ ref name, value, .. } => {
+            f.write_str(&format!("The label {} is at {}, which does not fit in a 16-bit operand field", name, value))
+          }
         }
     }
 }
@@ -49,21 +449,108 @@ impl Error for AssemblerError {
       AssemblerError::StringConstantDeclaredWithoutLabel{ .. } => {
         "Found a string constant without a corresponding label."
       }
-      AssemblerError::SymbolAlreadyDeclared => {
+      AssemblerError::SymbolAlreadyDeclared{ .. } => {
         "This symbol was previously declared"
       }
       AssemblerError::UnknownDirectiveFound{ .. } => {
         "Invalid or unknown directive found"
       }
-      AssemblerError::NonOpcodeInOpcodeField => {
+      AssemblerError::InvalidDirectiveOperands{ .. } => {
+        "A directive was given the wrong operands"
+      }
+      AssemblerError::IncludeFailed{ .. } => {
+        "An included file could not be read"
+      }
+      AssemblerError::IncludeCycle{ .. } => {
+        "Files include each other"
+      }
+      AssemblerError::InvalidMacroDefinition{ .. } => {
+        "Invalid macro definition"
+      }
+      AssemblerError::UnterminatedMacro{ .. } => {
+        "A macro is never ended"
+      }
+      AssemblerError::MacroArgumentMismatch{ .. } => {
+        "A macro was given the wrong number of arguments"
+      }
+      AssemblerError::MacroRecursionLimit{ .. } => {
+        "Macros are nested too deeply"
+      }
+      AssemblerError::NonOpcodeInOpcodeField{ .. } => {
         "A non-opcode was found in an opcode field"
       }
-      AssemblerError::InsufficientSections => {
+      AssemblerError::UnknownOpcode{ .. } => {
+        "Unknown opcode or macro"
+      }
+      AssemblerError::InvalidOperand{ .. } => {
+        "An operand cannot be encoded"
+      }
+      AssemblerError::OperandMismatch{ .. } => {
+        "An opcode was given the wrong operands"
+      }
+      AssemblerError::InvalidRegister{ .. } => {
+        "A register number is out of range"
+      }
+      AssemblerError::UndefinedSymbol{ .. } => {
+        "Undefined symbol"
+      }
+      AssemblerError::InsufficientSections{ .. } => {
         "Less than two sections/segments were found in the code"
       }
       AssemblerError::ParseError{ .. } => {
         "There was an error parsing the code"
       }
+      AssemblerError::LiteralOutOfRange{ .. } => {
+        "A literal does not fit in its operand field"
+      }
+      AssemblerError::InvalidIntegerLiteral{ .. } => {
+        "An integer literal is malformed or out of range"
+      }
+      AssemblerError::InvalidEscape{ .. } => {
+        "Invalid escape sequence in a string literal"
+      }
+      AssemblerError::UnterminatedString{ .. } => {
+        "Unterminated string literal"
+      }
+      AssemblerError::JumpTargetOutOfRange{ .. } => {
+        "A relative jump cannot reach its label"
+      }
+      AssemblerError::LabelOutOfRange{ .. } => {
+        "A label address does not fit in its operand field"
+      }
+    }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AssemblerWarning {
+    UnusedLabel { name: String, span: Span },
+}
+
+impl AssemblerWarning {
+    pub fn span(&self) -> &Span {
+        match self {
+            AssemblerWarning::UnusedLabel { span, .. } => span,
+        }
     }
+
+    /// Renders the warning with the source line it points at
+    pub fn render(&self) -> String {
+        format!(
+            "warning: {}\n{}\n{}",
+            self,
+            self.span().render(),
+            self.span().render_expansions()
+        )
+    }
+}
+
+impl fmt::Display for AssemblerWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AssemblerWarning::UnusedLabel { ref name, .. } => {
+                write!(f, "The label {} is declared but never used", name)
+            }
+        }
     }
 }
@@ -0,0 +1,66 @@
+use nom::types::CompleteStr;
+use nom::*;
+
+use crate::assembler::Token;
+
+named!(line_comment<CompleteStr, CompleteStr>,
+    recognize!(
+        preceded!(
+            tag!(";"),
+            take_till!(|c| c == '\n')
+        )
+    )
+);
+
+named!(block_comment<CompleteStr, CompleteStr>,
+    recognize!(
+        delimited!(
+            tag!("/*"),
+            take_until!("*/"),
+            tag!("*/")
+        )
+    )
+);
+
+// A `;` comment running to the end of the line or a `/* */` block, kept as
+// written so it can be reproduced
+named!(pub comment<CompleteStr, Token>,
+    do_parse!(
+        text: alt!(line_comment | block_comment) >>
+        (
+            Token::Comment{text: text.trim_end().to_string()}
+        )
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line_comment() {
+        let (rest, token) = comment(CompleteStr("; a comment  \nhlt")).unwrap();
+        assert_eq!(
+            token,
+            Token::Comment {
+                text: "; a comment".to_string()
+            }
+        );
+        assert_eq!(rest, CompleteStr("\nhlt"));
+        assert!(comment(CompleteStr(";")).is_ok());
+    }
+
+    #[test]
+    fn test_parse_block_comment() {
+        let (rest, token) = comment(CompleteStr("/* two\nlines */ hlt")).unwrap();
+        assert_eq!(
+            token,
+            Token::Comment {
+                text: "/* two\nlines */".to_string()
+            }
+        );
+        assert_eq!(rest, CompleteStr(" hlt"));
+        assert!(comment(CompleteStr("/* unterminated")).is_err());
+        assert!(comment(CompleteStr("hlt")).is_err());
+    }
+}
@@ -1,17 +1,23 @@
-use nom::alpha;
 use nom::types::CompleteStr;
+use nom::*;
 
+use crate::assembler::instruction_parsers::AssemblerInstruction;
 use crate::assembler::label_parsers::label_declaration;
+use crate::assembler::operand_parsers::operand;
+use crate::assembler::span::Span;
 use crate::assembler::Token;
 
-named!(pub directive <CompleteStr, Token>,
+// A directive and its operands, which are separated by commas or whitespace
+named!(pub directive_declaration <CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!(".") >>
             d: alpha >>
+            operands: many0!(terminated!(operand, opt!(tag!(",")))) >>
             (
                 Token::Directive{
                   name: d.to_string(),
+                  operands,
                 }
             )
         )
@@ -23,17 +29,16 @@ named!(directive_combined<CompleteStr, AssemblerInstruction>,
       do_parse!(
           l: opt!(label_declaration) >>
           name: directive_declaration >>
-          o1: opt!(operand) >>
-          o2: opt!(operand) >>
-          o3: opt!(operand) >>
           (
               AssemblerInstruction{
                   opcode: None,
                   directive: Some(name),
-                  label: 1,
-                  operand1: o1,
-                  operand2: o2,
-                  operand3: o3,
+                  label: l,
+                  operand1: None,
+                  operand2: None,
+                  operand3: None,
+                  comment: None,
+                  span: Span::default(),
               }
           )
       )
@@ -51,29 +56,45 @@ named!(pub directive<CompleteStr, AssemblerInstruction>,
   )
 );
 
+#[cfg(test)]
 mod tests {
-    #![allow(unused_imports)]
-    use super::directive;
-    use assembler::Token;
-    use nom::types::CompleteStr;
+    use super::*;
 
     #[test]
     fn test_parser_directive() {
-        let result = directive(CompleteStr(".data"));
-        assert_eq!(result.is_ok(), true);
+        let result = directive_declaration(CompleteStr(".data"));
+        assert!(result.is_ok());
         let (_, directive) = result.unwrap();
         assert_eq!(
             directive,
             Token::Directive {
-                name: "data".to_string()
+                name: "data".to_string(),
+                operands: vec![]
             }
         )
     }
 
+    #[test]
+    fn test_directive_operand_list() {
+        for source in &[".byte #1 #2 #3 #4 #5", ".byte #1, #2, #3,#4 , #5"] {
+            let (rest, directive) = directive_declaration(CompleteStr(source)).unwrap();
+            assert!(rest.is_empty(), "{}", source);
+            assert_eq!(
+                directive,
+                Token::Directive {
+                    name: "byte".to_string(),
+                    operands: (1..=5)
+                        .map(|value| Token::IntegerOperand { value })
+                        .collect()
+                }
+            );
+        }
+    }
+
     #[test]
     fn test_string_directive() {
         let result = directive_combined(CompleteStr("test: .asciiz 'Hello'"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, directive) = result.unwrap();
 
         let correct_instruction = AssemblerInstruction {
@@ -83,12 +104,15 @@ mod tests {
             }),
             directive: Some(Token::Directive {
                 name: "asciiz".to_string(),
+                operands: vec![Token::IrString {
+                    name: "Hello".to_string(),
+                }],
             }),
-            operand1: Some(Token::IrString {
-                name: "Hello".to_string(),
-            }),
+            operand1: None,
             operand2: None,
             operand3: None,
+            comment: None,
+            span: Span::default(),
         };
         assert_eq!(directive, correct_instruction);
     }
@@ -1,13 +1,21 @@
+use std::collections::HashMap;
+
 use nom::alt;
 use nom::do_parse;
 use nom::named;
 use nom::opt;
 use nom::types::CompleteStr;
+use nom::ws;
 
+use crate::assembler::assembler_errors::{describe_list, AssemblerError};
 use crate::assembler::label_parsers::label_declaration;
+use crate::assembler::macros::written_label;
 use crate::assembler::opcode_parsers::opcode;
 use crate::assembler::operand_parsers::operand;
+use crate::assembler::span::Span;
+use crate::assembler::symbols::closest;
 use crate::assembler::{SymbolTable, Token};
+use crate::instruction::{Opcode, OperandKind, REGISTER_COUNT};
 
 #[derive(Debug, PartialEq)]
 pub struct AssemblerInstruction {
@@ -17,35 +25,284 @@ pub struct AssemblerInstruction {
     pub operand1: Option<Token>,
     pub operand2: Option<Token>,
     pub operand3: Option<Token>,
+    /// A comment at the end of the line, or the only content of a line that
+    /// holds nothing but a comment
+    pub comment: Option<Token>,
+    /// Filled in by `program`, which knows where the instruction sits in the
+    /// source
+    pub span: Span,
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, _symbols: &SymbolTable) -> Vec<u8> {
+    /// Encodes the instruction. `offset` is its own address, in the same
+    /// address space as the symbol offsets, and is used to turn labels into
+    /// displacements for relative jumps.
+    pub fn to_bytes(&self, symbols: &SymbolTable, offset: u32) -> Result<Vec<u8>, AssemblerError> {
+        if self.is_directive() || self.is_comment() {
+            return Ok(vec![]);
+        }
+        self.check_operands()?;
+        if let Some((reg_num, value)) = self.wide_load() {
+            return Ok(AssemblerInstruction::expand_wide_load(reg_num, value));
+        }
+        if let Some((reg_num, value)) = self.float_load() {
+            return Ok(AssemblerInstruction::encode_float_load(reg_num, value));
+        }
+
         let mut results: Vec<u8> = vec![];
+        let code = match self.opcode {
+            Some(Token::Op { code }) => Some(code),
+            _ => None,
+        };
+        let byte_immediates = code.is_some_and(Opcode::is_heap_access);
         if let Some(ref token) = self.opcode {
             match token {
-                Token::Op { code } => match code {
-                    _ => {
-                        results.push(*code as u8);
-                    }
-                },
+                Token::Op { code } => {
+                    results.push(*code as u8);
+                }
                 _ => {
-                    println!("Non-opcode found ");
-                    std::process::exit(1);
+                    return Err(AssemblerError::NonOpcodeInOpcodeField {
+                        span: self.span.clone(),
+                    });
                 }
             };
         }
 
-        for operand in vec![&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(token) = operand {
-                AssemblerInstruction::extract_operand(token, &mut results, _symbols)
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            match (code, token) {
+                (Some(code), Token::LabelUsage { name }) if code.is_relative_jump() => {
+                    let target = self.resolve(name, symbols)?;
+                    let displacement =
+                        AssemblerInstruction::displacement(code, offset, target).unwrap_or(0);
+                    results.push((displacement >> 8) as u8);
+                    results.push(displacement as u8);
+                }
+                _ => self.extract_operand(token, &mut results, symbols, byte_immediates)?,
             }
         }
         while results.len() < 4 {
             results.push(0);
         }
 
-        return results;
+        Ok(results)
+    }
+
+    /// Checks the operands against the signature of the opcode, see
+    /// `Opcode::operands`
+    pub fn check_operands(&self) -> Result<(), AssemblerError> {
+        let code = match self.opcode {
+            Some(Token::Op { code }) => code,
+            _ => return Ok(()),
+        };
+        if code == Opcode::IGL {
+            let name = self.written_mnemonic();
+            let mnemonics: Vec<String> = Opcode::mnemonics().collect();
+            return Err(AssemblerError::UnknownOpcode {
+                name: name.to_string(),
+                suggestion: closest(name, mnemonics.iter().map(String::as_str)).map(str::to_string),
+                span: self.span.find(name),
+            });
+        }
+        let expected = code.operands();
+        let found: Vec<&Token> = [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+            .collect();
+        let matches = expected.len() == found.len()
+            && expected
+                .iter()
+                .zip(&found)
+                .all(|(kind, token)| AssemblerInstruction::accepts(*kind, token));
+        if !matches {
+            return Err(AssemblerError::OperandMismatch {
+                opcode: code,
+                expected,
+                found: describe_list(found.iter().map(|token| token.describe())),
+                span: self.span.clone(),
+            });
+        }
+        for token in found {
+            let (reg_num, written) = match *token {
+                Token::Register { reg_num } => (reg_num, format!("${}", reg_num)),
+                Token::FloatRegister { reg_num } => (reg_num, format!("$f{}", reg_num)),
+                _ => continue,
+            };
+            if reg_num >= REGISTER_COUNT {
+                return Err(AssemblerError::InvalidRegister {
+                    register: reg_num,
+                    span: self.span.find(&written),
+                });
+            }
+        }
+        if let Some(value) = self.out_of_range_literal() {
+            return Err(AssemblerError::LiteralOutOfRange {
+                value,
+                span: self.span.find(&format!("#{}", value)),
+            });
+        }
+        Ok(())
+    }
+
+    fn accepts(kind: OperandKind, token: &Token) -> bool {
+        matches!(
+            (kind, token),
+            (OperandKind::Register, Token::Register { .. })
+                | (OperandKind::FloatRegister, Token::FloatRegister { .. })
+                | (OperandKind::Byte, Token::IntegerOperand { .. })
+                | (OperandKind::Half, Token::IntegerOperand { .. })
+                | (OperandKind::Half, Token::LabelUsage { .. })
+                | (OperandKind::Displacement, Token::IntegerOperand { .. })
+                | (OperandKind::Displacement, Token::LabelUsage { .. })
+                | (OperandKind::Float, Token::FloatOperand { .. })
+        )
+    }
+
+    /// Number of 4-byte words this instruction adds to the code section
+    pub fn word_count(&self) -> u32 {
+        if self.is_directive() || self.is_comment() {
+            0
+        } else if self.wide_load().is_some() {
+            2
+        } else if self.float_load().is_some() {
+            3
+        } else {
+            1
+        }
+    }
+
+    /// Returns the first integer literal that does not fit the field it is
+    /// encoded into, see `literal_range`. Data directives check their own
+    /// literals.
+    pub fn out_of_range_literal(&self) -> Option<i32> {
+        let code = match self.opcode {
+            Some(Token::Op { code }) if self.wide_load().is_none() => code,
+            _ => return None,
+        };
+        code.operands()
+            .iter()
+            .zip(
+                [&self.operand1, &self.operand2, &self.operand3]
+                    .iter()
+                    .copied()
+                    .flatten(),
+            )
+            .filter_map(|(kind, token)| match token {
+                Token::IntegerOperand { value } => Some((*kind, *value)),
+                _ => None,
+            })
+            .find(|(kind, value)| {
+                let (min, max) = AssemblerInstruction::literal_range(code, *kind);
+                *value < min || *value > max
+            })
+            .map(|(_, value)| value)
+    }
+
+    /// Literals a field takes. Heap offsets are unsigned bytes, addresses and
+    /// JMPF/JMPB distances unsigned halves and JNEQ/JZ displacements signed
+    /// halves. The upper half LUI sets may be written either way.
+    fn literal_range(code: Opcode, kind: OperandKind) -> (i32, i32) {
+        match (kind, code) {
+            (OperandKind::Byte, _) => (0, i32::from(u8::MAX)),
+            (OperandKind::Displacement, Opcode::JNEQ) | (OperandKind::Displacement, Opcode::JZ) => {
+                (i32::from(i16::MIN), i32::from(i16::MAX))
+            }
+            (OperandKind::Half, Opcode::LUI) => (i32::from(i16::MIN), i32::from(u16::MAX)),
+            _ => (0, i32::from(u16::MAX)),
+        }
+    }
+
+    /// Returns the label of a relative jump whose target cannot be encoded from
+    /// `offset`: JMPF/JMPB only reach 65535 bytes in their own direction and
+    /// the conditional jumps take a signed 16-bit displacement
+    pub fn unreachable_jump_target(&self, symbols: &SymbolTable, offset: u32) -> Option<String> {
+        match (&self.opcode, &self.operand1) {
+            (Some(Token::Op { code }), Some(Token::LabelUsage { name }))
+                if code.is_relative_jump() =>
+            {
+                let target = symbols.symbol_value(name)?;
+                match AssemblerInstruction::displacement(*code, offset, target) {
+                    Some(_) => None,
+                    None => Some(name.clone()),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Encoded displacement from the jump at `offset` to `target`, if it fits
+    fn displacement(code: Opcode, offset: u32, target: u32) -> Option<u16> {
+        let distance = i64::from(target) - i64::from(offset);
+        match code {
+            Opcode::JMPF if distance >= 0 && distance <= i64::from(u16::MAX) => {
+                Some(distance as u16)
+            }
+            Opcode::JMPB if distance <= 0 && -distance <= i64::from(u16::MAX) => {
+                Some((-distance) as u16)
+            }
+            Opcode::JNEQ | Opcode::JZ
+                if distance >= i64::from(i16::MIN) && distance <= i64::from(i16::MAX) =>
+            {
+                Some(distance as i16 as u16)
+            }
+            _ => None,
+        }
+    }
+
+    /// `load $r #n` where `n` does not fit the zero-extended 16-bit LOAD field
+    fn wide_load(&self) -> Option<(u8, i32)> {
+        match (&self.opcode, &self.operand1, &self.operand2, &self.operand3) {
+            (
+                Some(Token::Op { code: Opcode::LOAD }),
+                Some(Token::Register { reg_num }),
+                Some(Token::IntegerOperand { value }),
+                None,
+            ) if *value < 0 || *value > i32::from(u16::MAX) => Some((*reg_num, *value)),
+            _ => None,
+        }
+    }
+
+    /// Loads the low half with LOAD, then sets the high half with LUI
+    fn expand_wide_load(reg_num: u8, value: i32) -> Vec<u8> {
+        let value = value as u32;
+        let low = value as u16;
+        let high = (value >> 16) as u16;
+        vec![
+            Opcode::LOAD as u8,
+            reg_num,
+            (low >> 8) as u8,
+            low as u8,
+            Opcode::LUI as u8,
+            reg_num,
+            (high >> 8) as u8,
+            high as u8,
+        ]
+    }
+
+    /// `loadf $fN #x` carries its f64 literal in the two words following the
+    /// instruction
+    fn float_load(&self) -> Option<(u8, f64)> {
+        match (&self.opcode, &self.operand1, &self.operand2, &self.operand3) {
+            (
+                Some(Token::Op {
+                    code: Opcode::LOADF,
+                }),
+                Some(Token::FloatRegister { reg_num }),
+                Some(Token::FloatOperand { value }),
+                None,
+            ) => Some((*reg_num, *value)),
+            _ => None,
+        }
+    }
+
+    fn encode_float_load(reg_num: u8, value: f64) -> Vec<u8> {
+        let mut results = vec![Opcode::LOADF as u8, reg_num, 0, 0];
+        results.extend_from_slice(&value.to_bits().to_be_bytes());
+        results
     }
 
     pub fn is_label(&self) -> bool {
@@ -54,43 +311,56 @@ impl AssemblerInstruction {
 
     pub fn label_name(&self) -> Option<String> {
         match &self.label {
-            Some(l) => match l {
-                Token::LabelDeclaration { name } => Some(name.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::LabelDeclaration { name }) => Some(name.clone()),
+            _ => None,
         }
     }
 
     pub fn get_label_name(&self) -> Option<String> {
         match &self.label {
-            Some(l) => match l {
-                Token::LabelDeclaration { name } => Some(name.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::LabelDeclaration { name }) => Some(name.clone()),
+            _ => None,
         }
     }
 
     pub fn get_directive_name(&self) -> Option<String> {
         match &self.directive {
-            Some(d) => match d {
-                Token::Directive { name } => Some(name.to_string()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::Directive { name, .. }) => Some(name.to_string()),
+            _ => None,
         }
     }
 
     pub fn has_operands(&self) -> bool {
-        self.operand1.is_some() || self.operand2.is_some() || self.operand3.is_some()
+        self.operands().next().is_some()
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
+    /// Operands of the instruction, or of the directive
+    pub fn operands(&self) -> impl Iterator<Item = &Token> {
+        let directive_operands = match &self.directive {
+            Some(Token::Directive { operands, .. }) => &operands[..],
+            _ => &[],
+        };
+        self.operand1
+            .iter()
+            .chain(&self.operand2)
+            .chain(&self.operand3)
+            .chain(directive_operands)
+    }
+
+    fn extract_operand(
+        &self,
+        t: &Token,
+        results: &mut Vec<u8>,
+        symbols: &SymbolTable,
+        byte_immediates: bool,
+    ) -> Result<(), AssemblerError> {
         match t {
-            Token::Register { reg_num } => {
+            Token::Register { reg_num } | Token::FloatRegister { reg_num } => {
                 results.push(*reg_num);
             }
+            Token::IntegerOperand { value } if byte_immediates => {
+                results.push(*value as u8);
+            }
             Token::IntegerOperand { value } => {
                 let converted = *value as u16;
                 let byte1 = converted;
@@ -98,20 +368,98 @@ impl AssemblerInstruction {
                 results.push(byte2 as u8);
                 results.push(byte1 as u8);
             }
-            Token::LabelUsage { name } => match symbols.symbol_value(name) {
-                Some(value) => {
-                    let byte1 = value;
-                    let byte2 = value >> 8;
-                    results.push(byte2 as u8);
-                    results.push(byte1 as u8);
+            Token::LabelUsage { name } => {
+                let value = self.resolve(name, symbols)?;
+                if value > u32::from(u16::MAX) {
+                    let name = written_label(name);
+                    return Err(AssemblerError::LabelOutOfRange {
+                        name: name.to_string(),
+                        value,
+                        span: self.span.find(&format!("@{}", name)),
+                    });
                 }
-                None => {}
-            },
+                let byte1 = value;
+                let byte2 = value >> 8;
+                results.push(byte2 as u8);
+                results.push(byte1 as u8);
+            }
             _ => {
-                println!("Opcode found in operand field");
-                std::process::exit(1);
+                return Err(AssemblerError::InvalidOperand {
+                    operand: t.describe().to_string(),
+                    span: self.span.clone(),
+                });
+            }
+        };
+        Ok(())
+    }
+
+    /// Looks up a label operand, suggesting a similar symbol if it was never
+    /// declared
+    fn resolve(&self, name: &str, symbols: &SymbolTable) -> Result<u32, AssemblerError> {
+        symbols
+            .symbol_value(name)
+            .ok_or_else(|| AssemblerError::UndefinedSymbol {
+                name: name.to_string(),
+                suggestion: symbols.suggest(name).map(str::to_string),
+                span: self.span.find(&format!("@{}", name)),
+            })
+    }
+
+    /// Values of the operands, or `None` if any of them is not an integer
+    /// literal
+    pub fn integer_operands(&self) -> Option<Vec<i32>> {
+        self.operands()
+            .map(|token| match token {
+                Token::IntegerOperand { value } => Some(*value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The mnemonic as written in the source, which for an unknown mnemonic
+    /// is all that is left of it after parsing
+    pub fn written_mnemonic(&self) -> &str {
+        let covered = self.span.covered();
+        // Skip a label declaration
+        let covered = match covered.find(':') {
+            Some(index) if covered[..index].chars().all(|c| c.is_ascii_alphanumeric()) => {
+                covered[index + 1..].trim_start()
             }
+            _ => covered,
+        };
+        let end = covered
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(covered.len());
+        &covered[..end]
+    }
+
+    /// Renames declared and used labels according to `renames`
+    pub fn rename_labels(&mut self, renames: &HashMap<String, String>) {
+        let directive_operands = match &mut self.directive {
+            Some(Token::Directive { operands, .. }) => &mut operands[..],
+            _ => &mut [],
         };
+        let tokens = std::iter::once(&mut self.label)
+            .chain(std::iter::once(&mut self.operand1))
+            .chain(std::iter::once(&mut self.operand2))
+            .chain(std::iter::once(&mut self.operand3))
+            .flatten()
+            .chain(directive_operands);
+        for token in tokens {
+            if let Token::LabelDeclaration { name } | Token::LabelUsage { name } = token {
+                if let Some(renamed) = renames.get(name) {
+                    *name = renamed.clone();
+                }
+            }
+        }
+    }
+
+    /// Names of the labels used as operands
+    pub fn label_usages(&self) -> impl Iterator<Item = &str> {
+        self.operands().filter_map(|token| match token {
+            Token::LabelUsage { name } => Some(name.as_str()),
+            _ => None,
+        })
     }
 
     pub fn is_opcode(&self) -> bool {
@@ -121,24 +469,33 @@ impl AssemblerInstruction {
     pub fn is_directive(&self) -> bool {
         self.directive.is_some()
     }
+
+    /// Whether this stands for a line holding only a comment
+    pub fn is_comment(&self) -> bool {
+        self.opcode.is_none() && self.directive.is_none() && self.comment.is_some()
+    }
 }
 
 named!(instruction_combined<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        l: opt!(label_declaration) >>
-        o: opcode >>
-        o1: opt!(operand) >>
-        o2: opt!(operand) >>
-        o3: opt!(operand) >>
-        (
-            AssemblerInstruction{
-                opcode: Some(o),
-                label: l,
-                directive: None,
-                operand1: o1,
-                operand2: o2,
-                operand3: o3,
-            }
+    ws!(
+        do_parse!(
+            l: opt!(label_declaration) >>
+            o: opcode >>
+            o1: opt!(operand) >>
+            o2: opt!(operand) >>
+            o3: opt!(operand) >>
+            (
+                AssemblerInstruction{
+                    opcode: Some(o),
+                    label: l,
+                    directive: None,
+                    operand1: o1,
+                    operand2: o2,
+                    operand3: o3,
+                    comment: None,
+                    span: Span::default(),
+                }
+            )
         )
     )
 );
@@ -156,5 +513,186 @@ named!(pub instruction<CompleteStr, AssemblerInstruction>,
 
 #[cfg(test)]
 mod tests {
-    //TODO: write tests
+    use super::*;
+    use crate::assembler::symbols::{Symbol, SymbolType};
+    use crate::instruction::Opcode;
+
+    #[test]
+    fn test_call_to_bytes() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new_with_offset(
+            "test".to_string(),
+            SymbolType::Label,
+            300,
+        ));
+        let (_, ins) = instruction(CompleteStr("call @test")).unwrap();
+        assert_eq!(ins.to_bytes(&symbols, 0).unwrap(), vec![18, 1, 44, 0]);
+        let (_, ins) = instruction(CompleteStr("ret")).unwrap();
+        assert_eq!(ins.opcode, Some(Token::Op { code: Opcode::RET }));
+        assert_eq!(ins.to_bytes(&symbols, 0).unwrap(), vec![19, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_push_pop_to_bytes() {
+        let symbols = SymbolTable::new();
+        let (_, ins) = instruction(CompleteStr("push $3")).unwrap();
+        assert_eq!(ins.to_bytes(&symbols, 0).unwrap(), vec![20, 3, 0, 0]);
+        let (_, ins) = instruction(CompleteStr("pop $4")).unwrap();
+        assert_eq!(ins.to_bytes(&symbols, 0).unwrap(), vec![21, 4, 0, 0]);
+    }
+
+    #[test]
+    fn test_heap_access_to_bytes() {
+        let symbols = SymbolTable::new();
+        let (_, ins) = instruction(CompleteStr("loadmw $1 $2 #8")).unwrap();
+        assert_eq!(ins.to_bytes(&symbols, 0).unwrap(), vec![24, 1, 2, 8]);
+        let (_, ins) = instruction(CompleteStr("setmb $3 $4 #255")).unwrap();
+        assert_eq!(ins.to_bytes(&symbols, 0).unwrap(), vec![25, 3, 4, 255]);
+    }
+
+    #[test]
+    fn test_wide_load_expands() {
+        let symbols = SymbolTable::new();
+        let (_, ins) = instruction(CompleteStr("load $0 #-70000")).unwrap();
+        assert_eq!(ins.word_count(), 2);
+        assert_eq!(ins.out_of_range_literal(), None);
+        // -70000 is 0xFFFE_EE90
+        assert_eq!(
+            ins.to_bytes(&symbols, 0).unwrap(),
+            vec![0, 0, 0xEE, 0x90, 31, 0, 0xFF, 0xFE]
+        );
+        let (_, ins) = instruction(CompleteStr("load $0 #65535")).unwrap();
+        assert_eq!(ins.word_count(), 1);
+        assert_eq!(ins.to_bytes(&symbols, 0).unwrap(), vec![0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_out_of_range_literal() {
+        let (_, ins) = instruction(CompleteStr("lui $0 #65536")).unwrap();
+        assert_eq!(ins.out_of_range_literal(), Some(65536));
+        let (_, ins) = instruction(CompleteStr("lui $0 #-32768")).unwrap();
+        assert_eq!(ins.out_of_range_literal(), None);
+        let (_, ins) = instruction(CompleteStr("loadmw $0 $1 #256")).unwrap();
+        assert_eq!(ins.out_of_range_literal(), Some(256));
+        let (_, ins) = instruction(CompleteStr("jz #40000")).unwrap();
+        assert_eq!(ins.out_of_range_literal(), Some(40000));
+        let (_, ins) = instruction(CompleteStr("jz #-40")).unwrap();
+        assert_eq!(ins.out_of_range_literal(), None);
+        let (_, ins) = instruction(CompleteStr("jmpf #40000")).unwrap();
+        assert_eq!(ins.out_of_range_literal(), None);
+        let (_, ins) = instruction(CompleteStr("call #-5")).unwrap();
+        assert_eq!(ins.out_of_range_literal(), Some(-5));
+        let (_, ins) = instruction(CompleteStr("prts #-1")).unwrap();
+        assert_eq!(ins.out_of_range_literal(), Some(-1));
+    }
+
+    #[test]
+    fn test_out_of_range_jump_literals_fail_to_encode() {
+        let symbols = SymbolTable::new();
+        for (source, value) in &[("jz #40000", 40000), ("call #-5", -5)] {
+            let (_, ins) = instruction(CompleteStr(source)).unwrap();
+            assert!(
+                matches!(
+                    ins.to_bytes(&symbols, 0),
+                    Err(AssemblerError::LiteralOutOfRange { value: v, .. }) if v == *value
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_float_instructions_to_bytes() {
+        let symbols = SymbolTable::new();
+        let (_, ins) = instruction(CompleteStr("loadf $f2 #1.5")).unwrap();
+        assert_eq!(ins.word_count(), 3);
+        assert_eq!(
+            ins.to_bytes(&symbols, 0).unwrap(),
+            vec![39, 2, 0, 0, 0x3F, 0xF8, 0, 0, 0, 0, 0, 0]
+        );
+        let (_, ins) = instruction(CompleteStr("addf $f0 $f1 $f2")).unwrap();
+        assert_eq!(ins.to_bytes(&symbols, 0).unwrap(), vec![40, 0, 1, 2]);
+        let (_, ins) = instruction(CompleteStr("itof $3 $f4")).unwrap();
+        assert_eq!(ins.to_bytes(&symbols, 0).unwrap(), vec![50, 3, 4, 0]);
+    }
+
+    #[test]
+    fn test_relative_jumps_to_bytes() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new_with_offset(
+            "back".to_string(),
+            SymbolType::Label,
+            100,
+        ));
+        symbols.add_symbol(Symbol::new_with_offset(
+            "ahead".to_string(),
+            SymbolType::Label,
+            400,
+        ));
+        let (_, ins) = instruction(CompleteStr("jmpf @ahead")).unwrap();
+        assert_eq!(ins.to_bytes(&symbols, 120).unwrap(), vec![52, 1, 24, 0]);
+        assert_eq!(ins.unreachable_jump_target(&symbols, 120), None);
+        assert_eq!(
+            ins.unreachable_jump_target(&symbols, 500),
+            Some("ahead".to_string())
+        );
+        let (_, ins) = instruction(CompleteStr("jmpb @back")).unwrap();
+        assert_eq!(ins.to_bytes(&symbols, 120).unwrap(), vec![53, 0, 20, 0]);
+        let (_, ins) = instruction(CompleteStr("jneq @back")).unwrap();
+        assert_eq!(
+            ins.to_bytes(&symbols, 120).unwrap(),
+            vec![54, 0xFF, 0xEC, 0]
+        );
+        let (_, ins) = instruction(CompleteStr("jz #8")).unwrap();
+        assert_eq!(ins.to_bytes(&symbols, 120).unwrap(), vec![55, 0, 8, 0]);
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let symbols = SymbolTable::new();
+        let (_, mut ins) = instruction(CompleteStr("foo $1")).unwrap();
+        ins.span = Span::new("", "foo $1", 0, 6);
+        match ins.to_bytes(&symbols, 0) {
+            Err(AssemblerError::UnknownOpcode { name, span, .. }) => {
+                assert_eq!(name, "foo");
+                assert_eq!((span.column, span.length), (1, 3));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_operand_signatures() {
+        let symbols = SymbolTable::new();
+        let (_, ins) = instruction(CompleteStr("hlt $1 #5 @x")).unwrap();
+        match ins.to_bytes(&symbols, 0) {
+            Err(AssemblerError::OperandMismatch {
+                opcode,
+                expected,
+                found,
+                ..
+            }) => {
+                assert_eq!(opcode, Opcode::HLT);
+                assert!(expected.is_empty());
+                assert_eq!(found, "a register, an integer literal and a label");
+            }
+            e => panic!("Unexpected result {:?}", e),
+        }
+        let (_, ins) = instruction(CompleteStr("add $1")).unwrap();
+        match ins.check_operands() {
+            e @ Err(AssemblerError::OperandMismatch { .. }) => assert_eq!(
+                e.unwrap_err().to_string(),
+                "add expects a register, a register and a register, but found a register"
+            ),
+            e => panic!("Unexpected result {:?}", e),
+        }
+        let (_, ins) = instruction(CompleteStr("load $32 #1")).unwrap();
+        assert!(matches!(
+            ins.check_operands(),
+            Err(AssemblerError::InvalidRegister { register: 32, .. })
+        ));
+        let (_, ins) = instruction(CompleteStr("loadf $f2 #1.5")).unwrap();
+        assert!(ins.check_operands().is_ok());
+    }
 }
@@ -37,7 +37,7 @@ mod tests {
     #[test]
     fn test_parse_label_declaration() {
         let result = label_declaration(CompleteStr("test:"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(
             token,
@@ -46,13 +46,13 @@ mod tests {
             }
         );
         let result = label_declaration(CompleteStr("test"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
     }
 
     #[test]
     fn test_parse_label_usage() {
         let result = label_usage(CompleteStr("@test"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(
             token,
@@ -61,6 +61,6 @@ mod tests {
             }
         );
         let result = label_usage(CompleteStr("test"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,274 @@
+use nom::types::CompleteStr;
+
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::span::Span;
+use crate::instruction::Opcode;
+
+/// How deeply macro invocations may nest before expansion is abandoned,
+/// which is what stops a macro that invokes itself
+pub const MACRO_DEPTH_LIMIT: usize = 16;
+
+/// Invocations parse like instructions, which take at most three operands
+pub const MACRO_PARAMETER_LIMIT: usize = 3;
+
+/// A macro defined with `.macro name param ...` and ended by `.endm`. Inside
+/// the body `\param` stands for the operand passed for `param`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    /// Source of the body, lines joined with newlines
+    pub body: String,
+    /// File the macro was defined in
+    pub file: String,
+    /// Line of `file` the body starts on
+    pub first_line: usize,
+    /// The `.macro` line
+    pub span: Span,
+}
+
+impl Macro {
+    /// The body with every `\param` replaced by the matching argument, padded
+    /// with blank lines so that it parses to spans on the lines of `file`
+    pub fn expand(&self, args: &[String]) -> String {
+        let mut expanded = "\n".repeat(self.first_line - 1);
+        let mut rest = self.body.as_str();
+        while let Some(index) = rest.find('\\') {
+            expanded.push_str(&rest[..index]);
+            rest = &rest[index..];
+            let length = rest[1..]
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len() - 1);
+            match self.params.iter().position(|p| *p == rest[1..=length]) {
+                Some(param) => {
+                    expanded.push_str(&args[param]);
+                    rest = &rest[length + 1..];
+                }
+                None => {
+                    expanded.push('\\');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+/// The name a label was written with. Labels declared in a macro body get
+/// `.n` appended to make each expansion's copy unique, which the label
+/// syntax itself cannot produce.
+pub fn written_label(name: &str) -> &str {
+    name.split('.').next().unwrap_or(name)
+}
+
+/// The text of a line up to any `;` comment, without surrounding whitespace
+fn code(line: &str) -> &str {
+    line.split(';').next().unwrap_or("").trim()
+}
+
+/// The words of a line up to any `;` comment, with their byte offsets
+fn words(line: &str) -> Vec<(usize, &str)> {
+    let code = line.split(';').next().unwrap_or("");
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in code
+        .char_indices()
+        .chain(std::iter::once((code.len(), ' ')))
+    {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                words.push((s, &code[s..i]));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    words
+}
+
+fn is_keyword(line: &str, keyword: &str) -> bool {
+    code(line).split_whitespace().next() == Some(keyword)
+}
+
+/// Takes the macro definitions out of `raw`, read from `file`. They are
+/// replaced by blank lines, so the rest of the source keeps its line numbers.
+pub fn extract_macros(file: &str, raw: &str) -> Result<(String, Vec<Macro>), AssemblerError> {
+    let mut source = String::with_capacity(raw.len());
+    let mut macros = vec![];
+    let mut lines = raw.split('\n').enumerate();
+    let mut offset = 0;
+    let line_span = |offset: usize, line: &str| {
+        let start = offset + (line.len() - line.trim_start().len());
+        Span::new(file, raw, start, offset + line.len())
+    };
+
+    while let Some((index, line)) = lines.next() {
+        let line_offset = offset;
+        offset += line.len() + 1;
+        if is_keyword(line, ".endm") {
+            return Err(AssemblerError::InvalidMacroDefinition {
+                reason: ".endm without a matching .macro",
+                span: line_span(line_offset, line),
+            });
+        }
+        if !is_keyword(line, ".macro") {
+            source.push_str(line);
+            source.push('\n');
+            continue;
+        }
+
+        let span = line_span(line_offset, line);
+        let header = words(line);
+        let word_span = |(start, word): (usize, &str)| {
+            Span::new(
+                file,
+                raw,
+                line_offset + start,
+                line_offset + start + word.len(),
+            )
+        };
+        let name = match header.get(1) {
+            Some((_, name)) if name.chars().all(|c| c.is_ascii_alphabetic()) => *name,
+            _ => {
+                return Err(AssemblerError::InvalidMacroDefinition {
+                    reason: "a macro name is made of letters",
+                    span,
+                })
+            }
+        };
+        if Opcode::from(CompleteStr(name)) != Opcode::IGL {
+            return Err(AssemblerError::InvalidMacroDefinition {
+                reason: "a macro cannot be named after an opcode",
+                span: word_span(header[1]),
+            });
+        }
+        let params = &header[2..];
+        if let Some(param) = params
+            .iter()
+            .find(|(_, p)| !p.chars().all(|c| c.is_ascii_alphanumeric()))
+        {
+            return Err(AssemblerError::InvalidMacroDefinition {
+                reason: "a parameter name is made of letters and digits",
+                span: word_span(*param),
+            });
+        }
+        if let Some(param) = params.get(MACRO_PARAMETER_LIMIT) {
+            return Err(AssemblerError::InvalidMacroDefinition {
+                reason: "a macro takes at most three parameters",
+                span: word_span(*param),
+            });
+        }
+        let params: Vec<String> = params.iter().map(|(_, p)| p.to_string()).collect();
+
+        let mut body = vec![];
+        loop {
+            let line = match lines.next() {
+                Some((_, line)) => line,
+                None => {
+                    return Err(AssemblerError::UnterminatedMacro {
+                        name: name.to_string(),
+                        span,
+                    })
+                }
+            };
+            let line_offset = offset;
+            offset += line.len() + 1;
+            source.push('\n');
+            if is_keyword(line, ".endm") {
+                break;
+            }
+            if is_keyword(line, ".macro") {
+                return Err(AssemblerError::InvalidMacroDefinition {
+                    reason: "macros cannot be defined inside a macro",
+                    span: line_span(line_offset, line),
+                });
+            }
+            body.push(line);
+        }
+        source.push('\n');
+        macros.push(Macro {
+            name: name.to_string(),
+            params,
+            body: body.join("\n"),
+            file: file.to_string(),
+            first_line: index + 2,
+            span,
+        });
+    }
+    // `split` gives one more line than there are newlines
+    source.pop();
+    Ok((source, macros))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_macros() {
+        let raw = ".code\n.macro inc reg ; adds one\nload $31 #1\nadd \\reg $31 \\reg\n.endm\nhlt";
+        let (source, macros) = extract_macros("test.iasm", raw).unwrap();
+        assert_eq!(source, ".code\n\n\n\n\nhlt");
+        assert_eq!(macros.len(), 1);
+        assert_eq!(macros[0].name, "inc");
+        assert_eq!(macros[0].params, vec!["reg".to_string()]);
+        assert_eq!(macros[0].first_line, 3);
+        assert_eq!(macros[0].span.line, 2);
+        assert_eq!(
+            macros[0].expand(&["$4".to_string()]),
+            "\n\nload $31 #1\nadd $4 $31 $4"
+        );
+    }
+
+    #[test]
+    fn test_expand_leaves_other_backslashes() {
+        let (_, macros) = extract_macros(
+            "",
+            ".macro say r rr\nx: .asciiz \"\\n\"\n\\rr \\r\\q\n.endm",
+        )
+        .unwrap();
+        assert_eq!(
+            macros[0].expand(&["A".to_string(), "B".to_string()]),
+            "\nx: .asciiz \"\\n\"\nB A\\q"
+        );
+    }
+
+    #[test]
+    fn test_too_many_macro_parameters() {
+        match extract_macros("", ".macro m a c b d\n.endm") {
+            Err(AssemblerError::InvalidMacroDefinition { reason, span }) => {
+                assert_eq!(reason, "a macro takes at most three parameters");
+                assert_eq!((span.column, span.length), (16, 1));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(extract_macros("", ".macro m a b c\n.endm").is_ok());
+    }
+
+    #[test]
+    fn test_invalid_macro_definitions() {
+        for raw in &[
+            ".macro\n.endm",
+            ".macro add\n.endm",
+            ".macro m a-b\n.endm",
+            ".macro m a b c d\n.endm",
+            ".macro m\n.macro n\n.endm\n.endm",
+            ".endm",
+        ] {
+            assert!(
+                matches!(
+                    extract_macros("", raw),
+                    Err(AssemblerError::InvalidMacroDefinition { .. })
+                ),
+                "{}",
+                raw
+            );
+        }
+        assert!(matches!(
+            extract_macros("", "hlt\n.macro m\nhlt\n"),
+            Err(AssemblerError::UnterminatedMacro { ref span, .. }) if span.line == 2
+        ));
+    }
+}
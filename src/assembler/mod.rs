@@ -1,32 +1,86 @@
 pub mod assembler_errors;
+pub mod comment_parsers;
+pub mod directive_parsers;
 pub mod instruction_parsers;
 pub mod label_parsers;
-pub mod opcode;
+pub mod macros;
 pub mod opcode_parsers;
 pub mod operand_parsers;
+pub mod pie_header;
 pub mod program_parsers;
 pub mod register_parsers;
+pub mod span;
 pub mod symbols;
 
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
 use nom::types::CompleteStr;
 
-use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::assembler_errors::{AssemblerError, AssemblerWarning};
 use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::macros::{extract_macros, written_label, Macro, MACRO_DEPTH_LIMIT};
+use crate::assembler::operand_parsers::{float_literal, string_literal};
+use crate::assembler::pie_header::PieHeader;
 use crate::assembler::program_parsers::{program, Program};
-use crate::assembler::symbols::{Symbol, SymbolTable, SymbolType};
+use crate::assembler::span::Span;
+use crate::assembler::symbols::{closest, Symbol, SymbolTable, SymbolType};
 use crate::instruction::Opcode;
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Op { code: Opcode },
     Register { reg_num: u8 },
+    FloatRegister { reg_num: u8 },
     IntegerOperand { value: i32 },
+    FloatOperand { value: f64 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
-    Directive { name: String },
+    Directive { name: String, operands: Vec<Token> },
     IrString { name: String },
+    Comment { text: String },
+}
+
+impl Token {
+    /// What kind of token this is, for error messages
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Token::Op { .. } => "an opcode",
+            Token::Register { .. } => "a register",
+            Token::FloatRegister { .. } => "a float register",
+            Token::IntegerOperand { .. } => "an integer literal",
+            Token::FloatOperand { .. } => "a float literal",
+            Token::LabelDeclaration { .. } => "a label declaration",
+            Token::LabelUsage { .. } => "a label",
+            Token::Directive { .. } => "a directive",
+            Token::IrString { .. } => "a string",
+            Token::Comment { .. } => "a comment",
+        }
+    }
+
+    /// The token written back as source, e.g. to substitute it into a macro
+    pub fn source(&self) -> String {
+        match self {
+            Token::Op { code } => code.mnemonic(),
+            Token::Register { reg_num } => format!("${}", reg_num),
+            Token::FloatRegister { reg_num } => format!("$f{}", reg_num),
+            Token::IntegerOperand { value } => format!("#{}", value),
+            Token::FloatOperand { value } => float_literal(*value),
+            Token::LabelDeclaration { name } => format!("{}:", name),
+            Token::LabelUsage { name } => format!("@{}", name),
+            Token::Directive { name, operands } => std::iter::once(format!(".{}", name))
+                .chain(operands.iter().map(Token::source))
+                .collect::<Vec<_>>()
+                .join(" "),
+            Token::IrString { name } => string_literal(name),
+            Token::Comment { text } => text.clone(),
+        }
+    }
 }
 
+/// A PIE image is a `PIE_HEADER_LENGTH` byte header, the read-only section
+/// and then the code. See `PieHeader` for the header layout.
 pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
 pub const PIE_HEADER_LENGTH: usize = 64;
 
@@ -39,7 +93,23 @@ pub struct Assembler {
     sections: Vec<AssemblerSection>,
     current_section: Option<AssemblerSection>,
     current_instruction: u32,
+    current_offset: u32,
     errors: Vec<AssemblerError>,
+    /// Problems that do not stop assembly, e.g. unused labels
+    pub warnings: Vec<AssemblerWarning>,
+    /// Directories searched for `.include`d files that are not found next to
+    /// the file including them
+    pub include_paths: Vec<PathBuf>,
+    macros: HashMap<String, Macro>,
+    /// Number of macro invocations expanded so far, used to make the labels
+    /// of each expansion unique
+    expansions: u32,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Assembler {
@@ -53,49 +123,298 @@ impl Assembler {
             sections: vec![],
             current_section: None,
             current_instruction: 0,
+            current_offset: 0,
             errors: vec![],
+            warnings: vec![],
+            include_paths: vec![],
+            macros: HashMap::new(),
+            expansions: 0,
         }
     }
 
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
-        match program(CompleteStr(raw)) {
-            Ok((_remainder, program)) => {
-                let mut assembled_program = self.write_pie_header();
-                self.process_first_phase(&program);
-
-                if !self.errors.is_empty() {
-                    println!("{:?}", self.errors);
-                    return Err(self.errors.clone());
-                }
+        self.assemble_named("<input>", raw)
+    }
+
+    /// Assembles `raw`, naming it `file` in the spans of any errors. Files it
+    /// includes are looked up relative to `file`.
+    pub fn assemble_named(
+        &mut self,
+        file: &str,
+        raw: &str,
+    ) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let mut including: Vec<PathBuf> = Path::new(file).canonicalize().into_iter().collect();
+        let program = match self.parse_file(file, raw, &mut including, None) {
+            Ok(program) => program,
+            Err(mut errors) => {
+                self.errors.append(&mut errors);
+                return Err(self.errors.clone());
+            }
+        };
+        self.process_first_phase(&program);
+        self.warn_unused_labels(&program);
+
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+
+        if self.sections.len() != 2 {
+            println!("Did not find at least two sections");
+            self.errors.push(AssemblerError::InsufficientSections {
+                span: program
+                    .instructions
+                    .first()
+                    .map(|i| i.span.clone())
+                    .unwrap_or_default(),
+            });
+            return Err(self.errors.clone());
+        }
+
+        let mut body = self.process_second_phase(&program);
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+
+        let mut assembled_program = self.write_pie_header(body.len() as u32);
+        assembled_program.extend_from_slice(&self.ro);
+        assembled_program.append(&mut body);
+        Ok(assembled_program)
+    }
+
+    /// Parses `raw`, read from `file`, replacing every `.include` with the
+    /// program of the file it names and every macro invocation with the
+    /// macro's body. `including` holds the canonical paths of the files being
+    /// parsed, to catch include cycles. `expanded_from` is the invocation
+    /// `raw` is the expansion of, if it is one.
+    fn parse_file(
+        &mut self,
+        file: &str,
+        raw: &str,
+        including: &mut Vec<PathBuf>,
+        expanded_from: Option<&Span>,
+    ) -> Result<Program, Vec<AssemblerError>> {
+        let expansion = expanded_from.map(|span| Box::new(span.clone()));
+        let with_expansion = |mut error: AssemblerError| {
+            error.span_mut().expanded_from = expansion.clone();
+            vec![error]
+        };
+        let (source, macros) = extract_macros(file, raw).map_err(with_expansion)?;
+        for m in macros {
+            if self.macros.contains_key(&m.name) {
+                return Err(with_expansion(AssemblerError::InvalidMacroDefinition {
+                    reason: "a macro with this name is already defined",
+                    span: m.span.find(&m.name),
+                }));
+            }
+            self.macros.insert(m.name.clone(), m);
+        }
+        if source.trim().is_empty() && source.len() != raw.len() {
+            // Nothing but macro definitions
+            return Ok(Program {
+                instructions: vec![],
+            });
+        }
 
-                if self.sections.len() != 2 {
-                    println!("Did not find at least two sections");
-                    self.errors.push(AssemblerError::InsufficientSections);
-                    return Err(self.errors.clone());
+        let parsed = match program(CompleteStr(&source)) {
+            Ok((remainder, _)) if !remainder.is_empty() => {
+                let start = source.len() - remainder.len();
+                return Err(with_expansion(AssemblerError::ParseError {
+                    error: "expected an instruction or a directive".to_string(),
+                    span: Span::new(file, &source, start, start + remainder.len()),
+                }));
+            }
+            Ok((_, parsed)) => parsed,
+            Err(e) => {
+                return Err(with_expansion(AssemblerError::from_parse_failure(
+                    file, &source, &e,
+                )))
+            }
+        };
+
+        let mut instructions = vec![];
+        let mut unknown = vec![];
+        for mut i in parsed.instructions {
+            i.span.file = file.to_string();
+            i.span.expanded_from = expansion.clone();
+            if i.get_directive_name().as_deref() == Some("include") {
+                let mut included = self.include(file, &i, including)?;
+                instructions.append(&mut included.instructions);
+            } else if i.opcode == Some(Token::Op { code: Opcode::IGL }) {
+                // Macros cannot be named after opcodes, so invocations parse
+                // as illegal instructions
+                match self.macros.get(i.written_mnemonic()).cloned() {
+                    Some(m) => {
+                        let mut expanded = self.expand_macro(&m, &i, including)?;
+                        instructions.append(&mut expanded.instructions);
+                    }
+                    None => unknown.push(self.unknown_mnemonic(&i)),
                 }
+            } else {
+                instructions.push(i);
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(unknown);
+        }
+        Ok(Program { instructions })
+    }
 
-                let mut body = self.process_second_phase(&program);
+    /// Error for an instruction whose mnemonic is neither an opcode nor a
+    /// macro, suggesting the closest of either
+    fn unknown_mnemonic(&self, i: &AssemblerInstruction) -> AssemblerError {
+        let name = i.written_mnemonic();
+        let mnemonics: Vec<String> = Opcode::mnemonics().collect();
+        let candidates = self.macros.keys().chain(&mnemonics).map(String::as_str);
+        AssemblerError::UnknownOpcode {
+            name: name.to_string(),
+            suggestion: closest(name, candidates).map(str::to_string),
+            span: i.span.find(name),
+        }
+    }
+
+    /// Expands the invocation `i` of the macro `m`. Labels declared in the
+    /// body are renamed to be unique to this expansion, and a label on the
+    /// invocation is given to the first instruction of the expansion.
+    fn expand_macro(
+        &mut self,
+        m: &Macro,
+        i: &AssemblerInstruction,
+        including: &mut Vec<PathBuf>,
+    ) -> Result<Program, Vec<AssemblerError>> {
+        let args: Vec<String> = i.operands().map(Token::source).collect();
+        if args.len() != m.params.len() {
+            return Err(vec![AssemblerError::MacroArgumentMismatch {
+                name: m.name.clone(),
+                expected: m.params.len(),
+                found: args.len(),
+                span: i.span.clone(),
+            }]);
+        }
+        let mut depth = 1;
+        let mut invocation = i.span.expanded_from.as_deref();
+        while let Some(span) = invocation {
+            depth += 1;
+            invocation = span.expanded_from.as_deref();
+        }
+        if depth > MACRO_DEPTH_LIMIT {
+            return Err(vec![AssemblerError::MacroRecursionLimit {
+                name: m.name.clone(),
+                span: i.span.clone(),
+            }]);
+        }
+
+        let body = m.expand(&args);
+        if body.trim().is_empty() {
+            return Ok(Program {
+                instructions: vec![],
+            });
+        }
+        let mut expanded = self.parse_file(&m.file, &body, including, Some(&i.span))?;
 
-                assembled_program.append(&mut body);
-                Ok(assembled_program)
+        self.expansions += 1;
+        let mut renames: HashMap<String, String> = expanded
+            .instructions
+            .iter()
+            .filter_map(AssemblerInstruction::label_name)
+            // Labels of nested expansions are already unique
+            .filter(|name| !name.contains('.'))
+            .map(|name| {
+                let unique = format!("{}.{}", name, self.expansions);
+                (name, unique)
+            })
+            .collect();
+        let first = expanded.instructions.iter_mut().find(|e| !e.is_comment());
+        if let (Some(label), Some(first)) = (i.label_name(), first) {
+            match first.label_name() {
+                Some(name) => {
+                    renames.insert(name, label);
+                }
+                None => first.label = Some(Token::LabelDeclaration { name: label }),
+            }
+        }
+        for e in &mut expanded.instructions {
+            e.rename_labels(&renames);
+        }
+        Ok(expanded)
+    }
+
+    /// Parses the file named by the `.include` directive `i` in `file`. The
+    /// name is resolved relative to `file`, then to each of the include paths.
+    fn include(
+        &mut self,
+        file: &str,
+        i: &AssemblerInstruction,
+        including: &mut Vec<PathBuf>,
+    ) -> Result<Program, Vec<AssemblerError>> {
+        let name = match i.operands().collect::<Vec<_>>()[..] {
+            [Token::IrString { name }] => name,
+            _ => {
+                return Err(vec![AssemblerError::InvalidDirectiveOperands {
+                    directive: "include".to_string(),
+                    expected: "a file name",
+                    span: i.span.clone(),
+                }])
             }
+        };
+        let span = i.span.find(name);
+        let base = Path::new(file).parent().unwrap_or_else(|| Path::new(""));
+        let path = match std::iter::once(base)
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+            .map(|directory| directory.join(name))
+            .find(|path| path.is_file())
+        {
+            Some(path) => path,
+            None => {
+                return Err(vec![AssemblerError::IncludeFailed {
+                    path: name.clone(),
+                    reason: io::ErrorKind::NotFound,
+                    span,
+                }])
+            }
+        };
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if including.contains(&canonical) {
+            return Err(vec![AssemblerError::IncludeCycle {
+                path: name.clone(),
+                span,
+            }]);
+        }
+        let raw = match std::fs::read_to_string(&path) {
+            Ok(raw) => raw,
             Err(e) => {
-                println!("There was an error assembling the code: {:?}", e);
-                Err(vec![AssemblerError::ParseError {
-                    error: e.to_string(),
+                return Err(vec![AssemblerError::IncludeFailed {
+                    path: name.clone(),
+                    reason: e.kind(),
+                    span,
                 }])
             }
+        };
+        if raw.trim().is_empty() {
+            return Ok(Program {
+                instructions: vec![],
+            });
         }
+
+        including.push(canonical);
+        let included = self.parse_file(
+            &path.to_string_lossy(),
+            &raw,
+            including,
+            i.span.expanded_from.as_deref(),
+        );
+        including.pop();
+        included
     }
 
     fn process_first_phase(&mut self, p: &Program) {
-        for i in &p.instructions {
+        for i in p.instructions.iter().filter(|i| !i.is_comment()) {
             if i.is_label() {
                 if self.current_section.is_some() {
-                    self.process_label_declaration(&i);
+                    self.process_label_declaration(i);
                 } else {
                     self.errors.push(AssemblerError::NoSegmentDeclarationFound {
                         instruction: self.current_instruction,
+                        span: i.span.clone(),
                     })
                 }
             }
@@ -103,30 +422,63 @@ impl Assembler {
             if i.is_directive() {
                 self.process_directive(i);
             }
+
             self.current_instruction += 1;
+            self.current_offset += 4 * i.word_count();
         }
 
+        let code_start = self.code_start();
+        self.symbols.rebase(SymbolType::Label, code_start);
         self.phase = AssemblerPhase::Second;
     }
 
     fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
         let mut program = vec![];
-        for i in &p.instructions {
-            let mut bytes = i.to_bytes(&self.symbols);
-            program.append(&mut bytes);
+        self.current_instruction = 0;
+        self.current_offset = 0;
+        for i in p.instructions.iter().filter(|i| !i.is_comment()) {
+            let address = self.code_start() + self.current_offset;
+            if let Some(label) = i.unreachable_jump_target(&self.symbols, address) {
+                let label = written_label(&label).to_string();
+                self.errors.push(AssemblerError::JumpTargetOutOfRange {
+                    instruction: self.current_instruction,
+                    span: i.span.find(&format!("@{}", label)),
+                    label,
+                });
+            }
+            match i.to_bytes(&self.symbols, address) {
+                Ok(mut bytes) => program.append(&mut bytes),
+                Err(e) => self.errors.push(e),
+            }
+            self.current_instruction += 1;
+            self.current_offset += 4 * i.word_count();
         }
         program
     }
 
-    fn write_pie_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in PIE_HEADER_PREFIX.into_iter() {
-            header.push(byte.clone());
-        }
-        while header.len() <= PIE_HEADER_LENGTH {
-            header.push(0 as u8);
+    fn warn_unused_labels(&mut self, p: &Program) {
+        let used: HashSet<&str> = p
+            .instructions
+            .iter()
+            .flat_map(AssemblerInstruction::label_usages)
+            .collect();
+        for symbol in &self.symbols.symbols {
+            if !used.contains(symbol.name()) {
+                self.warnings.push(AssemblerWarning::UnusedLabel {
+                    name: written_label(symbol.name()).to_string(),
+                    span: symbol.span().cloned().unwrap_or_default(),
+                });
+            }
         }
-        header
+    }
+
+    /// Address of the first instruction in the PIE image
+    fn code_start(&self) -> u32 {
+        (PIE_HEADER_LENGTH + self.ro.len()) as u32
+    }
+
+    fn write_pie_header(&self, code_length: u32) -> Vec<u8> {
+        PieHeader::new(self.ro.len() as u32, code_length).to_bytes()
     }
 
     fn process_label_declaration(&mut self, i: &AssemblerInstruction) {
@@ -136,19 +488,31 @@ impl Assembler {
                 self.errors
                     .push(AssemblerError::StringConstantDeclaredWithoutLabel {
                         instruction: self.current_instruction,
+                        span: i.span.clone(),
                     });
                 return;
             }
         };
 
-        if self.symbols.has_symbol(&name) {
-            self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+        let span = i.span.find(&name);
+        if let Some(original) = self.symbols.symbol(&name) {
+            self.errors.push(AssemblerError::SymbolAlreadyDeclared {
+                span,
+                original: Box::new(original.span().cloned().unwrap_or_default()),
+                name,
+            });
             return;
         }
 
-        let symbol =
-            Symbol::new_with_offset(name, SymbolType::Label, (self.current_instruction * 4) + 60);
-        self.symbols.add_symbol(symbol);
+        // Labels on directives are given their read-only offset once the
+        // directive is processed; code labels are rebased onto the code
+        // section after the first phase
+        let symbol = if i.is_directive() {
+            Symbol::new(name, SymbolType::Data, 0)
+        } else {
+            Symbol::new_with_offset(name, SymbolType::Label, self.current_offset)
+        };
+        self.symbols.add_symbol(symbol.with_span(span));
     }
 
     fn process_directive(&mut self, i: &AssemblerInstruction) {
@@ -160,55 +524,134 @@ impl Assembler {
             }
         };
 
-        if i.has_operands() {
-            match directive_name.as_ref() {
-                "asciiz" => {
-                    self.handle_asciiz(i);
-                }
-                "integer" => {
-                    self.handle_asciiz(i);
-                }
-                _ => {
-                    self.errors.push(AssemblerError::UnknownDirectiveFound {
-                        directive: directive_name.clone(),
-                    });
-                    return;
-                }
+        match directive_name.as_ref() {
+            "asciiz" => self.handle_asciiz(i),
+            "byte" => self.handle_integers(i, 1),
+            "half" => self.handle_integers(i, 2),
+            "integer" | "word" => self.handle_integers(i, 4),
+            "space" => self.handle_space(i),
+            "align" => self.handle_align(i),
+            _ if i.has_operands() => {
+                self.errors.push(AssemblerError::UnknownDirectiveFound {
+                    directive: directive_name.clone(),
+                    span: i.span.find(&format!(".{}", directive_name)),
+                });
             }
-        } else {
-            self.process_section_header(&directive_name);
+            _ => self.process_section_header(&directive_name),
+        }
+    }
+
+    /// Appends `bytes` to the read-only section
+    fn push_ro(&mut self, bytes: &[u8]) {
+        self.ro.extend_from_slice(bytes);
+        self.ro_offset += bytes.len() as u32;
+    }
+
+    /// Points the label of a data directive, if it has one, at the end of the
+    /// read-only section, where its data is about to be written
+    fn place_data_label(&mut self, i: &AssemblerInstruction) {
+        if let Some(name) = i.get_label_name() {
+            self.symbols.set_symbol_offset(&name, self.ro_offset);
         }
     }
 
+    fn invalid_directive_operands(&mut self, i: &AssemblerInstruction, expected: &'static str) {
+        self.errors.push(AssemblerError::InvalidDirectiveOperands {
+            directive: i.get_directive_name().unwrap_or_default(),
+            expected,
+            span: i.span.clone(),
+        });
+    }
+
     fn handle_asciiz(&mut self, i: &AssemblerInstruction) {
         if self.phase != AssemblerPhase::First {
             return;
         }
 
-        match i.get_label_name() {
-            Some(s) => {
-                match i.get_label_name() {
-                    Some(name) => {
-                        self.symbols.set_symbol_offset(&name, self.ro_offset);
-                    }
-                    None => {
-                        println!("Found a string constant with no associated label");
-                        return;
-                    }
-                };
-                for byte in s.as_bytes() {
-                    self.ro.push(*byte);
-                    self.ro_offset += 1;
-                }
-                self.ro.push(0);
-                self.ro_offset += 1;
-            }
-            None => {
-                println!("String constant following an .asciiz was empty");
-            }
+        let s = match i.operands().collect::<Vec<_>>()[..] {
+            [Token::IrString { name }] => name,
+            _ => return self.invalid_directive_operands(i, "a string"),
+        };
+        if !i.is_label() {
+            self.errors
+                .push(AssemblerError::StringConstantDeclaredWithoutLabel {
+                    instruction: self.current_instruction,
+                    span: i.span.clone(),
+                });
+            return;
+        }
+        self.place_data_label(i);
+        self.push_ro(s.as_bytes());
+        self.push_ro(&[0]);
+    }
+
+    /// Lays out the operands of `.byte`, `.half`, `.word` or `.integer` as
+    /// big-endian integers of `width` bytes
+    fn handle_integers(&mut self, i: &AssemblerInstruction, width: usize) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+
+        let values = match i.integer_operands() {
+            Some(ref values) if !values.is_empty() => values.clone(),
+            _ => return self.invalid_directive_operands(i, "integer literals"),
+        };
+        // Like instruction fields, narrow values may be written signed or
+        // unsigned
+        let (min, max) = match width {
+            1 => (i64::from(i8::MIN), i64::from(u8::MAX)),
+            2 => (i64::from(i16::MIN), i64::from(u16::MAX)),
+            _ => (i64::from(i32::MIN), i64::from(i32::MAX)),
+        };
+        if let Some(value) = values
+            .iter()
+            .copied()
+            .find(|value| i64::from(*value) < min || i64::from(*value) > max)
+        {
+            self.errors.push(AssemblerError::LiteralOutOfRange {
+                value,
+                span: i.span.find(&format!("#{}", value)),
+            });
+            return;
+        }
+
+        self.place_data_label(i);
+        for value in values {
+            self.push_ro(&value.to_be_bytes()[4 - width..]);
         }
     }
 
+    /// Reserves `.space #n` zeroed bytes
+    fn handle_space(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+
+        let size = match i.integer_operands().as_deref() {
+            Some([size]) if (0..=i32::from(u16::MAX)).contains(size) => *size as usize,
+            _ => return self.invalid_directive_operands(i, "a size from #0 to #65535"),
+        };
+        self.place_data_label(i);
+        self.push_ro(&vec![0; size]);
+    }
+
+    /// Pads the read-only section with zeroes up to a multiple of `.align #n`
+    fn handle_align(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+
+        let alignment = match i.integer_operands().as_deref() {
+            Some([alignment]) if *alignment > 0 && (*alignment as u32).is_power_of_two() => {
+                *alignment as u32
+            }
+            _ => return self.invalid_directive_operands(i, "a power of two"),
+        };
+        let padding = (alignment - self.ro_offset % alignment) % alignment;
+        self.push_ro(&vec![0; padding as usize]);
+        self.place_data_label(i);
+    }
+
     fn process_section_header(&mut self, header_name: &str) {
         let mut new_section: AssemblerSection = header_name.into();
         if new_section == AssemblerSection::Unknown {
@@ -248,20 +691,19 @@ pub enum AssemblerPhase {
     Second,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub enum AssemblerSection {
-    Data { starting_instruction: Option<u32> },
-    Code { starting_instruction: Option<u32> },
+    Data {
+        starting_instruction: Option<u32>,
+    },
+    Code {
+        starting_instruction: Option<u32>,
+    },
+    #[default]
     Unknown,
 }
 
-impl Default for AssemblerSection {
-    fn default() -> Self {
-        AssemblerSection::Unknown
-    }
-}
-
-impl<'a> From<&'a str> for AssemblerSection {
+impl From<&str> for AssemblerSection {
     fn from(name: &str) -> AssemblerSection {
         match name {
             "data" => AssemblerSection::Data {
@@ -282,8 +724,544 @@ fn test_symbol_table() {
     sym.add_symbol(new_symbol);
     assert_eq!(sym.symbols.len(), 1);
     let v = sym.symbol_value("test");
-    assert_eq!(false, v.is_some());
+    assert!(v.is_none());
     assert_eq!(v, None);
     let v = sym.symbol_value("none");
-    assert_eq!(v.is_some(), false);
+    assert!(v.is_none());
+}
+
+#[test]
+fn test_literal_out_of_range() {
+    let mut asm = Assembler::new();
+    let errors = asm
+        .assemble(".data\n.code\nload $0 #-70000\nlui $1 #70000\nhlt\n")
+        .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    match errors[0] {
+        AssemblerError::LiteralOutOfRange { value, ref span } => {
+            assert_eq!(value, 70000);
+            assert_eq!((span.line, span.column, span.length), (4, 8, 6));
+        }
+        ref e => panic!("Unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_assemble_program_with_ro_section() {
+    let mut asm = Assembler::new();
+    let test_string = ".data\nhello: .asciiz 'Hello'\n.code\nprts @hello\nhlt\n";
+    let program = asm.assemble(test_string).unwrap();
+    let header = PieHeader::parse(&program).unwrap();
+    assert_eq!(header, PieHeader::new(6, 8));
+    assert_eq!(
+        &program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 6],
+        b"Hello\0"
+    );
+    assert_eq!(
+        &program[PIE_HEADER_LENGTH + 6..],
+        &[17, 0, 0, 0, 6, 0, 0, 0]
+    );
+    assert_eq!(asm.symbols.symbol_value("hello"), Some(0));
+}
+
+#[test]
+fn test_comments_do_not_change_output() {
+    let plain = ".data\nhello: .asciiz 'Hello'\n.code\nprts @hello\nhlt\n";
+    let commented = "; greeting\n\n.data ; strings\nhello: .asciiz 'Hi; there' ; not a comment inside\n\n/* the\n   code */\n.code\nprts @hello;print\n\nhlt\n; done";
+    let expected = Assembler::new()
+        .assemble(&plain.replace("Hello", "Hi; there"))
+        .unwrap();
+    assert_eq!(Assembler::new().assemble(commented).unwrap(), expected);
+}
+
+#[test]
+fn test_integer_literal_forms() {
+    let mut asm = Assembler::new();
+    let program = asm
+        .assemble(".data\n.code\nload $0 #0xFF\nload $1 #0b101\nload $2 #'A'\nload $3 #-1\n")
+        .unwrap();
+    assert_eq!(
+        &program[PIE_HEADER_LENGTH..],
+        // #-1 does not fit 16 bits unsigned, so it also sets the upper half
+        &[0, 0, 0, 255, 0, 1, 0, 5, 0, 2, 0, 65, 0, 3, 255, 255, 31, 3, 255, 255]
+    );
+}
+
+#[test]
+fn test_invalid_integer_literal() {
+    let mut asm = Assembler::new();
+    let errors = asm
+        .assemble(".data\n.code\nload $0 #99999999999 ; too big\nhlt\n")
+        .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    match errors[0] {
+        AssemblerError::InvalidIntegerLiteral {
+            ref literal,
+            ref span,
+        } => {
+            assert_eq!(literal, "#99999999999");
+            assert_eq!((span.line, span.column, span.length), (3, 9, 12));
+        }
+        ref e => panic!("unexpected error {:?}", e),
+    }
+    let errors = Assembler::new()
+        .assemble(".data\n.code\nload $0 #0xZZ\n")
+        .unwrap_err();
+    assert_eq!(errors[0].span().find("#0xZZ").column, 9);
+    let errors = Assembler::new()
+        .assemble(".data\n.code\nload $0 #  99999999999\n")
+        .unwrap_err();
+    match errors[0] {
+        AssemblerError::InvalidIntegerLiteral {
+            ref literal,
+            ref span,
+        } => {
+            assert_eq!(literal, "#  99999999999");
+            assert_eq!((span.line, span.column, span.length), (3, 9, 14));
+        }
+        ref e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_escaped_string_in_ro_section() {
+    let mut asm = Assembler::new();
+    let program = asm
+        .assemble(".data\nhi: .asciiz \"a\\tb\\n\"\n.code\nprts @hi\nhlt\n")
+        .unwrap();
+    assert_eq!(
+        &program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 5],
+        b"a\tb\n\0"
+    );
+}
+
+#[test]
+fn test_invalid_escape() {
+    let errors = Assembler::new()
+        .assemble(".data\nhi: .asciiz \"a\\qb\"\n.code\nhlt\n")
+        .unwrap_err();
+    match errors[0] {
+        AssemblerError::InvalidEscape {
+            ref escape,
+            ref span,
+        } => {
+            assert_eq!(escape, "\\q");
+            assert_eq!((span.line, span.column, span.length), (2, 15, 2));
+        }
+        ref e => panic!("unexpected error {:?}", e),
+    }
+    let errors = Assembler::new()
+        .assemble(".data\nhi: .asciiz \"open\n.code\nhlt\n")
+        .unwrap_err();
+    assert!(matches!(
+        errors[0],
+        AssemblerError::UnterminatedString { ref span } if span.column == 13
+    ));
+}
+
+#[test]
+fn test_label_past_64k_is_rejected() {
+    let source = ".data\n.space #65535\n.space #65535\n.code\ncall @end\nend: hlt\n";
+    let errors = Assembler::new().assemble(source).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    match errors[0] {
+        AssemblerError::LabelOutOfRange {
+            ref name,
+            value,
+            ref span,
+        } => {
+            assert_eq!(name, "end");
+            assert!(value > u32::from(u16::MAX));
+            assert_eq!((span.line, span.column, span.length), (5, 6, 4));
+        }
+        ref e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_data_directives() {
+    let mut asm = Assembler::new();
+    let source = ".data\nb: .byte #1 #-1 #0xFF\nh: .half #-2, #3, #-4, #5\n.align #4\nw: .word #0x01020304\ni: .integer #7\ns: .space #3\nmsg: .asciiz 'hi'\n.code\nload $0 @w\nhlt\n";
+    asm.assemble(source).unwrap();
+    assert_eq!(
+        asm.ro,
+        vec![
+            1, 255, 255, 255, 254, 0, 3, 255, 252, 0, 5, 0, 1, 2, 3, 4, 0, 0, 0, 7, 0, 0, 0, b'h',
+            b'i', 0
+        ]
+    );
+    let offsets: Vec<Option<u32>> = ["b", "h", "w", "i", "s", "msg"]
+        .iter()
+        .map(|name| asm.symbols.symbol_value(name))
+        .collect();
+    assert_eq!(
+        offsets,
+        vec![Some(0), Some(3), Some(12), Some(16), Some(20), Some(23)]
+    );
+}
+
+#[test]
+fn test_invalid_data_directives() {
+    let source = ".data\nb: .byte #256\ns: .space 'x'\n.align #3\n.asciiz 'no label'\n.code\nhlt\n";
+    let errors = Assembler::new().assemble(source).unwrap_err();
+    assert_eq!(errors.len(), 4);
+    assert!(matches!(
+        errors[0],
+        AssemblerError::LiteralOutOfRange { value: 256, .. }
+    ));
+    assert_eq!(
+        errors[1].to_string(),
+        "The directive .space expects a size from #0 to #65535"
+    );
+    assert_eq!(
+        errors[2].to_string(),
+        "The directive .align expects a power of two"
+    );
+    assert!(matches!(
+        errors[3],
+        AssemblerError::StringConstantDeclaredWithoutLabel { .. }
+    ));
+}
+
+/// A fresh directory holding the files of an include test, removed when the
+/// test is done with it
+#[cfg(test)]
+struct IncludeFixture {
+    directory: PathBuf,
+}
+
+#[cfg(test)]
+impl std::ops::Deref for IncludeFixture {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.directory
+    }
+}
+
+#[cfg(test)]
+impl Drop for IncludeFixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+/// Writes `files` to a directory no other test or test run uses
+#[cfg(test)]
+fn include_fixture(name: &str, files: &[(&str, &str)]) -> IncludeFixture {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos();
+    let directory = std::env::temp_dir().join(format!(
+        "basalt-{}-{}-{}-{}",
+        name,
+        std::process::id(),
+        FIXTURES.fetch_add(1, Ordering::SeqCst),
+        nanos
+    ));
+    let fixture = IncludeFixture { directory };
+    for (path, contents) in files {
+        let path = fixture.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+    fixture
+}
+
+#[test]
+fn test_include_relative_to_including_file() {
+    let directory = include_fixture(
+        "relative",
+        &[
+            (
+                "main.iasm",
+                ".data\n.include \"lib/strings.iasm\"\n.code\nprts @hello\nhlt\n",
+            ),
+            (
+                "lib/strings.iasm",
+                "; strings\n.include \"more.iasm\"\nhello: .asciiz 'Hello'\n",
+            ),
+            ("lib/more.iasm", "bye: .asciiz 'Bye'\n"),
+        ],
+    );
+    let main = directory.join("main.iasm");
+    let source = std::fs::read_to_string(&main).unwrap();
+    let mut asm = Assembler::new();
+    asm.assemble_named(&main.to_string_lossy(), &source)
+        .unwrap();
+    assert_eq!(asm.ro, b"Bye\0Hello\0");
+    assert_eq!(asm.symbols.symbol_value("hello"), Some(4));
+    // The unused label is reported in the file that declares it
+    assert!(asm.warnings[0].span().file.ends_with("more.iasm"));
+}
+
+#[test]
+fn test_include_search_path() {
+    let directory = include_fixture("search", &[("lib/defs.iasm", "x: .word #1\n")]);
+    let source = ".data\n.include \"defs.iasm\"\n.code\nload $0 @x\nhlt\n";
+    let errors = Assembler::new().assemble(source).unwrap_err();
+    assert!(matches!(
+        errors[0],
+        AssemblerError::IncludeFailed { ref span, .. } if span.column == 11 && span.length == 9
+    ));
+    let mut asm = Assembler::new();
+    asm.include_paths.push(directory.join("lib"));
+    asm.assemble(source).unwrap();
+    assert_eq!(asm.ro, vec![0, 0, 0, 1]);
+}
+
+#[test]
+fn test_include_cycle() {
+    let directory = include_fixture(
+        "cycle",
+        &[
+            ("a.iasm", ".data\n.include \"b.iasm\"\n.code\nhlt\n"),
+            ("b.iasm", ".include \"a.iasm\"\n"),
+        ],
+    );
+    let a = directory.join("a.iasm");
+    let source = std::fs::read_to_string(&a).unwrap();
+    let errors = Assembler::new()
+        .assemble_named(&a.to_string_lossy(), &source)
+        .unwrap_err();
+    match errors[0] {
+        AssemblerError::IncludeCycle { ref path, ref span } => {
+            assert_eq!(path, "a.iasm");
+            assert!(span.file.ends_with("b.iasm"));
+            assert_eq!(span.line, 1);
+        }
+        ref e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_errors_in_included_files_name_them() {
+    let directory = include_fixture("errors", &[("bad.iasm", "hlt\nload $0 #1.5\n")]);
+    let source = format!(
+        ".data\n.code\n.include \"{}\"\n",
+        directory.join("bad.iasm").display()
+    );
+    let errors = Assembler::new().assemble(&source).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    let span = errors[0].span();
+    assert!(span.file.ends_with("bad.iasm"));
+    assert_eq!((span.line, &*span.text), (2, "load $0 #1.5"));
+}
+
+#[test]
+fn test_macro_expansion() {
+    let source = ".macro countdown reg\nloop: sub \\reg $31 \\reg\njz @loop\n.endm\n.macro twice a b\ncountdown \\a\ncountdown \\b\n.endm\n.data\n.code\nload $31 #1\nstart: twice $1 $2\njmpb @start\n";
+    let expected = ".data\n.code\nload $31 #1\nstart: sub $1 $31 $1\njz @start\nb: sub $2 $31 $2\njz @b\njmpb @start\n";
+    let mut asm = Assembler::new();
+    let program = asm.assemble(source).unwrap();
+    assert_eq!(program, Assembler::new().assemble(expected).unwrap());
+    // Each expansion gets its own copy of the local label
+    assert_eq!(asm.symbols.symbol_value("loop.2"), Some(76));
+    assert!(!asm.symbols.has_symbol("loop"));
+}
+
+#[test]
+fn test_unused_macro_label_warning_uses_written_name() {
+    let source = ".macro spin\nagain: nop\n.endm\n.data\n.code\nspin\nhlt\n";
+    let mut asm = Assembler::new();
+    asm.assemble(source).unwrap();
+    assert_eq!(asm.warnings.len(), 1);
+    match asm.warnings[0] {
+        AssemblerWarning::UnusedLabel { ref name, ref span } => {
+            assert_eq!(name, "again");
+            assert_eq!(span.line, 2);
+            assert_eq!(span.expanded_from.as_ref().unwrap().line, 6);
+        }
+    }
+    assert!(asm.warnings[0]
+        .render()
+        .contains("note: in the macro invoked here"));
+}
+
+#[test]
+fn test_macro_errors_point_at_invocation_and_body() {
+    let source = ".data\n.code\n.macro bad reg\nadd \\reg #1 $0\n.endm\nbad $1\nbad $1 $2\n";
+    let errors = Assembler::new().assemble(source).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(
+        errors[0],
+        AssemblerError::MacroArgumentMismatch { expected: 1, found: 2, ref span, .. } if span.line == 7
+    ));
+
+    let errors = Assembler::new()
+        .assemble(".data\n.code\n.macro bad reg\nadd \\reg #1 $0\n.endm\nbad $1\n")
+        .unwrap_err();
+    let span = errors[0].span();
+    assert_eq!((span.line, &*span.text), (4, "add $1 #1 $0"));
+    assert_eq!(span.expanded_from.as_ref().unwrap().line, 6);
+    assert!(errors[0].render().ends_with(
+        "note: in the macro invoked here\n --> <input>:6:1\n  |\n6 | bad $1\n  | ^^^^^^\n"
+    ));
+}
+
+#[test]
+fn test_unknown_macro_suggests_closest() {
+    let source =
+        ".macro countdown reg\nsub \\reg $31 \\reg\n.endm\n.data\n.code\ncountdwn $1\nlod $2 #1\n";
+    let errors = Assembler::new().assemble(source).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].to_string(), "Unknown opcode or macro `countdwn`");
+    assert_eq!(errors[0].hint(), "did you mean `countdown`?");
+    assert_eq!(errors[0].span().line, 6);
+    assert_eq!(errors[1].hint(), "did you mean `load`?");
+}
+
+#[test]
+fn test_macro_arguments_come_from_operands() {
+    let source = ".macro say text\nmsg: .asciiz \\text\n.endm\n.data\nsay \"it's\"\nsay \"a \\\"b\\\"\\n\"\n.code\nhlt\n";
+    let mut asm = Assembler::new();
+    asm.assemble(source).unwrap();
+    assert_eq!(asm.ro, b"it's\0a \"b\"\n\0");
+}
+
+#[test]
+fn test_macro_recursion_limit() {
+    let errors = Assembler::new()
+        .assemble(".data\n.code\n.macro forever\nforever\n.endm\nforever\n")
+        .unwrap_err();
+    match errors[0] {
+        AssemblerError::MacroRecursionLimit { ref name, ref span } => {
+            assert_eq!(name, "forever");
+            assert_eq!(span.line, 4);
+        }
+        ref e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_unknown_opcode_fails_to_assemble() {
+    let errors = Assembler::new()
+        .assemble(".data\n.code\nhlx\nhlt\n")
+        .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), "Unknown opcode or macro `hlx`");
+    assert_eq!(errors[0].hint(), "did you mean `hlt`?");
+    let span = errors[0].span();
+    assert_eq!((span.line, span.column, span.length), (3, 1, 3));
+}
+
+#[test]
+fn test_code_labels_point_past_ro_section() {
+    let mut asm = Assembler::new();
+    let test_string = ".data\nhi: .asciiz 'Hi'\n.code\nload $0 #1\nend: hlt\n";
+    asm.assemble(test_string).unwrap();
+    assert_eq!(
+        asm.symbols.symbol_value("end"),
+        Some(PIE_HEADER_LENGTH as u32 + 3 + 4)
+    );
+}
+
+#[test]
+fn test_symbol_already_declared_points_at_both_labels() {
+    let mut asm = Assembler::new();
+    let errors = asm
+        .assemble_named(
+            "dup.iasm",
+            ".data\n.code\nfoo: hlt\nload $0 #1\n  foo: hlt\n",
+        )
+        .unwrap_err();
+    match errors[0] {
+        AssemblerError::SymbolAlreadyDeclared {
+            ref name,
+            ref span,
+            ref original,
+        } => {
+            assert_eq!(name, "foo");
+            assert_eq!((span.line, span.column), (5, 3));
+            assert_eq!((original.line, original.column), (3, 1));
+        }
+        ref e => panic!("Unexpected error {:?}", e),
+    }
+    assert_eq!(
+        errors[0].render(),
+        "error: The symbol foo was previously declared.\n \
+         --> dup.iasm:5:3\n  |\n5 |   foo: hlt\n  |   ^^^\n  = hint: rename one of the labels\n\
+         note: foo was first declared here\n \
+         --> dup.iasm:3:1\n  |\n3 | foo: hlt\n  | ^^^\n"
+    );
+}
+
+#[test]
+fn test_parse_error_points_at_bad_line() {
+    let mut asm = Assembler::new();
+    let errors = asm.assemble(".data\n.code\nhlt\n  %bad\n").unwrap_err();
+    match errors[0] {
+        AssemblerError::ParseError { ref span, .. } => {
+            assert_eq!(span.file, "<input>");
+            assert_eq!((span.line, span.column, span.length), (4, 3, 4));
+        }
+        ref e => panic!("Unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_encoding_errors_are_collected() {
+    let mut asm = Assembler::new();
+    let errors = asm
+        .assemble(".data\n.code\nload $0 'a'\nhlt\nadd $0 #1.5 $1\n")
+        .unwrap_err();
+    assert_eq!(errors.len(), 2);
+    match errors[1] {
+        AssemblerError::OperandMismatch {
+            ref found,
+            ref span,
+            ..
+        } => {
+            assert_eq!(found, "a register, a float literal and a register");
+            assert_eq!(span.line, 5);
+        }
+        ref e => panic!("Unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_undefined_symbol_suggests_declared_label() {
+    let mut asm = Assembler::new();
+    let errors = asm
+        .assemble(".data\n.code\nloop: jmpb @lop\ncall @nowhere\n")
+        .unwrap_err();
+    assert_eq!(errors.len(), 2);
+    match errors[0] {
+        AssemblerError::UndefinedSymbol {
+            ref name,
+            ref suggestion,
+            ref span,
+        } => {
+            assert_eq!(name, "lop");
+            assert_eq!(suggestion.as_deref(), Some("loop"));
+            assert_eq!((span.line, span.column, span.length), (3, 12, 4));
+        }
+        ref e => panic!("Unexpected error {:?}", e),
+    }
+    assert_eq!(errors[0].hint(), "did you mean @loop?");
+    assert!(matches!(
+        errors[1],
+        AssemblerError::UndefinedSymbol {
+            suggestion: None,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_unused_label_warning() {
+    let mut asm = Assembler::new();
+    asm.assemble(".data\nhi: .asciiz 'Hi'\n.code\nstart: prts @hi\nend: jmpb @start\n")
+        .unwrap();
+    assert_eq!(asm.warnings.len(), 1);
+    match asm.warnings[0] {
+        AssemblerWarning::UnusedLabel { ref name, ref span } => {
+            assert_eq!(name, "end");
+            assert_eq!(span.line, 5);
+        }
+    }
 }
@@ -35,7 +35,7 @@ mod tests {
     #[test]
     fn test_opcode() {
         let result = opcode(CompleteStr("load"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::LOAD });
         assert_eq!(rest, CompleteStr(""));
@@ -1,18 +1,100 @@
+use std::convert::TryFrom;
+
 use crate::assembler::label_parsers::label_usage;
-use crate::assembler::register_parsers::register;
+use crate::assembler::register_parsers::{float_register, register};
 use nom::named;
 use nom::types::CompleteStr;
 use nom::*;
 
 use crate::assembler::Token;
 
+/// `ErrorKind::Custom` code of the failure raised when a `#` is not followed
+/// by a valid integer literal
+pub const INVALID_INTEGER_LITERAL: u32 = 1;
+/// `ErrorKind::Custom` code of the failure raised at a backslash that does not
+/// start a valid escape sequence
+pub const INVALID_ESCAPE: u32 = 2;
+/// `ErrorKind::Custom` code of the failure raised at a `"` that is not closed
+/// on the same line
+pub const UNTERMINATED_STRING: u32 = 3;
+
 named!(integer_operand<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("#") >>
-            reg_num: digit >>
+            value: integer_literal >>
+            (
+                Token::IntegerOperand{value}
+            )
+        )
+    )
+);
+
+/// Value of the integer literal following a `#`: an optionally negative
+/// decimal, `0x` hexadecimal or `0b` binary number, or a character, possibly
+/// escaped, in single quotes. If it is malformed or does not fit in an `i32` this is a failure
+/// rather than an error, so no other operand parser is tried.
+fn integer_literal(input: CompleteStr) -> IResult<CompleteStr, i32> {
+    let fail = || {
+        Err(Err::Failure(Context::Code(
+            input,
+            ErrorKind::Custom(INVALID_INTEGER_LITERAL),
+        )))
+    };
+    if let Some(quoted) = input.strip_prefix('\'') {
+        let (c, length) = match quoted.chars().next() {
+            Some('\\') => match escape(quoted) {
+                Some(escaped) => escaped,
+                None => return fail(),
+            },
+            Some(c) if c != '\'' => (c, c.len_utf8()),
+            _ => return fail(),
+        };
+        return match quoted[length..].strip_prefix('\'') {
+            Some(rest) => Ok((CompleteStr(rest), c as i32)),
+            None => fail(),
+        };
+    }
+
+    let (negative, unsigned) = match input.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, &input[..]),
+    };
+    let (radix, number) = match unsigned.get(..2) {
+        Some("0x") | Some("0X") => (16, &unsigned[2..]),
+        Some("0b") | Some("0B") => (2, &unsigned[2..]),
+        _ => (10, unsigned),
+    };
+    // Take every alphanumeric so that e.g. `0b12` is rejected rather than
+    // read as `0b1` followed by garbage
+    let length = number
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(number.len());
+    let (digits, rest) = number.split_at(length);
+    let value = match i64::from_str_radix(digits, radix) {
+        Ok(value) if negative => -value,
+        Ok(value) => value,
+        Err(_) => return fail(),
+    };
+    match i32::try_from(value) {
+        Ok(value) => Ok((CompleteStr(rest), value)),
+        Err(_) => fail(),
+    }
+}
+
+named!(float_operand<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("#") >>
+            sign: opt!(tag!("-")) >>
+            whole: digit >>
+            tag!(".") >>
+            fraction: digit >>
             (
-                Token::IntegerOperand{value: reg_num.parse::<i32>().unwrap()}
+                {
+                    let value = format!("{}.{}", whole, fraction).parse::<f64>().unwrap();
+                    Token::FloatOperand{value: if sign.is_some() { -value } else { value }}
+                }
             )
         )
     )
@@ -20,14 +102,24 @@ named!(integer_operand<CompleteStr, Token>,
 
 named!(pub operand<CompleteStr, Token>,
     alt!(
+        float_operand |
         integer_operand |
         label_usage |
         register |
+        float_register |
         irstring
     )
 );
 
 named!(irstring<CompleteStr, Token>,
+    alt!(
+        raw_string |
+        quoted_string
+    )
+);
+
+// A single-quoted string, taken as written
+named!(raw_string<CompleteStr, Token>,
     do_parse!(
         tag!("'") >>
         content: take_until!("'") >>
@@ -38,21 +130,241 @@ named!(irstring<CompleteStr, Token>,
     )
 );
 
+/// A double-quoted string, in which a backslash starts an escape sequence
+fn quoted_string(input: CompleteStr) -> IResult<CompleteStr, Token> {
+    let content = match input.strip_prefix('"') {
+        Some(content) => content,
+        None => return Err(Err::Error(Context::Code(input, ErrorKind::Tag))),
+    };
+    let mut name = String::new();
+    let mut rest = content;
+    loop {
+        let c = match rest.chars().next() {
+            Some(c) if c != '\n' => c,
+            _ => {
+                return Err(Err::Failure(Context::Code(
+                    input,
+                    ErrorKind::Custom(UNTERMINATED_STRING),
+                )))
+            }
+        };
+        match c {
+            '"' => break,
+            '\\' => match escape(rest) {
+                Some((escaped, length)) => {
+                    name.push(escaped);
+                    rest = &rest[length..];
+                }
+                None => {
+                    let position = &input[input.len() - rest.len()..];
+                    return Err(Err::Failure(Context::Code(
+                        CompleteStr(position),
+                        ErrorKind::Custom(INVALID_ESCAPE),
+                    )));
+                }
+            },
+            _ => {
+                name.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    Ok((CompleteStr(&rest[1..]), Token::IrString { name }))
+}
+
+/// Decodes the escape sequence `input` starts with, returning the character
+/// and the length of the sequence. `\x` is limited to ASCII so that strings
+/// stay valid UTF-8.
+fn escape(input: &str) -> Option<(char, usize)> {
+    let mut chars = input.strip_prefix('\\')?.chars();
+    let c = match chars.next()? {
+        'n' => '\n',
+        't' => '\t',
+        '0' => '\0',
+        '\\' => '\\',
+        '"' => '"',
+        '\'' => '\'',
+        'x' => {
+            let value = hex_value(input.get(2..4)?).filter(|value| *value < 0x80)?;
+            return Some((char::from(value as u8), 4));
+        }
+        'u' => {
+            let digits = input.get(2..)?.strip_prefix('{')?;
+            let end = digits.find('}')?;
+            if end == 0 || end > 6 {
+                return None;
+            }
+            let value = hex_value(&digits[..end])?;
+            return Some((std::char::from_u32(value)?, end + 4));
+        }
+        _ => return None,
+    };
+    Some((c, 2))
+}
+
+/// Value of the hexadecimal digits of an escape. `from_str_radix` alone would
+/// also take a leading `+`.
+fn hex_value(digits: &str) -> Option<u32> {
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// Writes a string as a literal: single-quoted if it can be taken as written,
+/// otherwise double-quoted with escapes
+pub fn string_literal(string: &str) -> String {
+    if !string.contains('\'') && !string.chars().any(char::is_control) {
+        return format!("'{}'", string);
+    }
+    let mut literal = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '\n' => literal.push_str("\\n"),
+            '\t' => literal.push_str("\\t"),
+            '\\' => literal.push_str("\\\\"),
+            '"' => literal.push_str("\\\""),
+            c if c.is_ascii_control() => literal.push_str(&format!("\\x{:02x}", c as u32)),
+            c if c.is_control() => literal.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Writes a finite float as a literal. Display never uses an exponent, but
+/// drops the fraction of whole numbers, which the float literal syntax
+/// requires.
+pub fn float_literal(value: f64) -> String {
+    let literal = format!("{}", value);
+    if literal.contains('.') {
+        format!("#{}", literal)
+    } else {
+        format!("#{}.0", literal)
+    }
+}
+
 mod tests {
     #![allow(unused_imports)]
-    use super::integer_operand;
+    use super::{
+        float_operand, integer_operand, irstring, operand, INVALID_ESCAPE, INVALID_INTEGER_LITERAL,
+        UNTERMINATED_STRING,
+    };
     use crate::assembler::Token;
     use nom::types::CompleteStr;
+    use nom::{Context, Err, ErrorKind};
 
     #[test]
     fn test_parse_integer_operand() {
         let result = integer_operand(CompleteStr("#10"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, value) = result.unwrap();
         assert_eq!(rest, CompleteStr(""));
         assert_eq!(value, Token::IntegerOperand { value: 10 });
 
+        let result = integer_operand(CompleteStr("#-70000"));
+        assert_eq!(result.unwrap().1, Token::IntegerOperand { value: -70000 });
+
         let result = integer_operand(CompleteStr("10"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_integer_literal_forms() {
+        for (literal, expected) in &[
+            ("#-1", -1),
+            ("#0xFF", 255),
+            ("#0Xff", 255),
+            ("#-0x10", -16),
+            ("#0b1010", 10),
+            ("#'A'", 65),
+            ("#' '", 32),
+            ("#'\\n'", 10),
+            ("#'\\''", 39),
+            ("#2147483647", i32::MAX),
+            ("#-2147483648", i32::MIN),
+        ] {
+            let (rest, token) = integer_operand(CompleteStr(literal)).unwrap();
+            assert_eq!(rest, CompleteStr(""), "{}", literal);
+            assert_eq!(token, Token::IntegerOperand { value: *expected });
+        }
+    }
+
+    #[test]
+    fn test_invalid_integer_literal_fails() {
+        for literal in &[
+            "#2147483648",
+            "#99999999999999999999999",
+            "#0x",
+            "#0b102",
+            "#12ab",
+            "#''",
+            "#'ab'",
+            "#-",
+        ] {
+            match integer_operand(CompleteStr(literal)) {
+                Err(Err::Failure(Context::Code(position, ErrorKind::Custom(code)))) => {
+                    assert_eq!(code, INVALID_INTEGER_LITERAL);
+                    assert_eq!(position, CompleteStr(&literal[1..]));
+                }
+                result => panic!("{} parsed as {:?}", literal, result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_float_operand() {
+        let result = float_operand(CompleteStr("#1.75"));
+        assert_eq!(result.unwrap().1, Token::FloatOperand { value: 1.75 });
+        let result = float_operand(CompleteStr("#-0.5"));
+        assert_eq!(result.unwrap().1, Token::FloatOperand { value: -0.5 });
+        assert!(float_operand(CompleteStr("#3")).is_err());
+
+        let result = operand(CompleteStr("#2.5"));
+        assert_eq!(result.unwrap().1, Token::FloatOperand { value: 2.5 });
+        let result = operand(CompleteStr("$f7"));
+        assert_eq!(result.unwrap().1, Token::FloatRegister { reg_num: 7 });
+    }
+
+    #[test]
+    fn test_parse_quoted_string() {
+        let (rest, token) =
+            irstring(CompleteStr(r#""tab\there\n\"quoted\" \\ \x41\u{e9}\0" x"#)).unwrap();
+        assert_eq!(rest, CompleteStr(" x"));
+        assert_eq!(
+            token,
+            Token::IrString {
+                name: "tab\there\n\"quoted\" \\ A\u{e9}\0".to_string()
+            }
+        );
+        let (_, token) = irstring(CompleteStr(r#"'raw \n'"#)).unwrap();
+        assert_eq!(
+            token,
+            Token::IrString {
+                name: r#"raw \n"#.to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_quoted_string_fails() {
+        for (source, code, position) in &[
+            (r#""bad \q""#, INVALID_ESCAPE, r#"\q""#),
+            (r#""\x80""#, INVALID_ESCAPE, r#"\x80""#),
+            (r#""\u{110000}""#, INVALID_ESCAPE, r#"\u{110000}""#),
+            (r#""\u{}""#, INVALID_ESCAPE, r#"\u{}""#),
+            (r#""\x+1""#, INVALID_ESCAPE, r#"\x+1""#),
+            (r#""\u{+41}""#, INVALID_ESCAPE, r#"\u{+41}""#),
+            ("\"open\n\"", UNTERMINATED_STRING, "\"open\n\""),
+            (r#""open"#, UNTERMINATED_STRING, r#""open"#),
+        ] {
+            match irstring(CompleteStr(source)) {
+                Err(Err::Failure(Context::Code(at, ErrorKind::Custom(c)))) => {
+                    assert_eq!((c, at), (*code, CompleteStr(position)), "{}", source);
+                }
+                result => panic!("{} parsed as {:?}", source, result),
+            }
+        }
     }
 }
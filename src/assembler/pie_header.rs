@@ -0,0 +1,231 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
+
+/// Format version written by this assembler and the only one the VM runs
+pub const PIE_VERSION: u16 = 1;
+
+/// The `PIE_HEADER_LENGTH` byte header at the start of every PIE image. All
+/// fields are big-endian and offsets are from the start of the image:
+///
+/// | bytes  | field                                  |
+/// |--------|----------------------------------------|
+/// | 0..4   | `PIE_HEADER_PREFIX`                    |
+/// | 4..6   | format version                         |
+/// | 6..8   | flags, currently always zero           |
+/// | 8..12  | entry point                            |
+/// | 12..16 | read-only section offset               |
+/// | 16..20 | read-only section length               |
+/// | 20..24 | code section offset                    |
+/// | 24..28 | code section length                    |
+/// | 28..64 | reserved, zero                         |
+#[derive(Debug, Clone, PartialEq)]
+pub struct PieHeader {
+    pub version: u16,
+    pub flags: u16,
+    pub entry_point: u32,
+    pub ro_offset: u32,
+    pub ro_length: u32,
+    pub code_offset: u32,
+    pub code_length: u32,
+}
+
+impl PieHeader {
+    /// Header for an image laid out as header, read-only section and then
+    /// code, starting execution at the first instruction
+    pub fn new(ro_length: u32, code_length: u32) -> PieHeader {
+        let ro_offset = PIE_HEADER_LENGTH as u32;
+        let code_offset = ro_offset + ro_length;
+        PieHeader {
+            version: PIE_VERSION,
+            flags: 0,
+            entry_point: code_offset,
+            ro_offset,
+            ro_length,
+            code_offset,
+            code_length,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = vec![0; PIE_HEADER_LENGTH];
+        header[0..4].copy_from_slice(&PIE_HEADER_PREFIX);
+        BigEndian::write_u16(&mut header[4..6], self.version);
+        BigEndian::write_u16(&mut header[6..8], self.flags);
+        BigEndian::write_u32(&mut header[8..12], self.entry_point);
+        BigEndian::write_u32(&mut header[12..16], self.ro_offset);
+        BigEndian::write_u32(&mut header[16..20], self.ro_length);
+        BigEndian::write_u32(&mut header[20..24], self.code_offset);
+        BigEndian::write_u32(&mut header[24..28], self.code_length);
+        header
+    }
+
+    /// Parses the header at the start of `image` and checks that its sections
+    /// and entry point lie within the image
+    pub fn parse(image: &[u8]) -> Result<PieHeader, PieHeaderError> {
+        if image.len() < PIE_HEADER_LENGTH {
+            return Err(PieHeaderError::Truncated {
+                length: image.len(),
+            });
+        }
+        if image[0..4] != PIE_HEADER_PREFIX {
+            return Err(PieHeaderError::BadPrefix);
+        }
+        let version = BigEndian::read_u16(&image[4..6]);
+        if version != PIE_VERSION {
+            return Err(PieHeaderError::UnsupportedVersion { version });
+        }
+        let header = PieHeader {
+            version,
+            flags: BigEndian::read_u16(&image[6..8]),
+            entry_point: BigEndian::read_u32(&image[8..12]),
+            ro_offset: BigEndian::read_u32(&image[12..16]),
+            ro_length: BigEndian::read_u32(&image[16..20]),
+            code_offset: BigEndian::read_u32(&image[20..24]),
+            code_length: BigEndian::read_u32(&image[24..28]),
+        };
+        if header.flags != 0 {
+            return Err(PieHeaderError::UnsupportedFlags {
+                flags: header.flags,
+            });
+        }
+        for (section, range) in &[
+            ("read-only", header.ro_range()),
+            ("code", header.code_range()),
+        ] {
+            if range.start < PIE_HEADER_LENGTH || range.start > range.end || range.end > image.len()
+            {
+                return Err(PieHeaderError::SectionOutOfBounds { section });
+            }
+        }
+        if !header.code_range().contains(&(header.entry_point as usize)) {
+            return Err(PieHeaderError::EntryPointOutOfBounds {
+                entry_point: header.entry_point,
+            });
+        }
+        Ok(header)
+    }
+
+    pub fn ro_range(&self) -> Range<usize> {
+        let start = self.ro_offset as usize;
+        start..start.saturating_add(self.ro_length as usize)
+    }
+
+    pub fn code_range(&self) -> Range<usize> {
+        let start = self.code_offset as usize;
+        start..start.saturating_add(self.code_length as usize)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PieHeaderError {
+    Truncated { length: usize },
+    BadPrefix,
+    UnsupportedVersion { version: u16 },
+    UnsupportedFlags { flags: u16 },
+    SectionOutOfBounds { section: &'static str },
+    EntryPointOutOfBounds { entry_point: u32 },
+}
+
+impl fmt::Display for PieHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PieHeaderError::Truncated { length } => write!(
+                f,
+                "The image is {} bytes long, too short for a {} byte PIE header",
+                length, PIE_HEADER_LENGTH
+            ),
+            PieHeaderError::BadPrefix => f.write_str("The image does not start with a PIE header"),
+            PieHeaderError::UnsupportedVersion { version } => write!(
+                f,
+                "Unsupported PIE format version {}; only version {} is supported",
+                version, PIE_VERSION
+            ),
+            PieHeaderError::UnsupportedFlags { flags } => {
+                write!(f, "Unsupported PIE header flags {:#06x}", flags)
+            }
+            PieHeaderError::SectionOutOfBounds { section } => {
+                write!(f, "The {} section lies outside the image", section)
+            }
+            PieHeaderError::EntryPointOutOfBounds { entry_point } => write!(
+                f,
+                "The entry point {} is outside the code section",
+                entry_point
+            ),
+        }
+    }
+}
+
+impl Error for PieHeaderError {
+    fn description(&self) -> &str {
+        match self {
+            PieHeaderError::Truncated { .. } => "Truncated PIE header",
+            PieHeaderError::BadPrefix => "Missing PIE header prefix",
+            PieHeaderError::UnsupportedVersion { .. } => "Unsupported PIE format version",
+            PieHeaderError::UnsupportedFlags { .. } => "Unsupported PIE header flags",
+            PieHeaderError::SectionOutOfBounds { .. } => "PIE section out of bounds",
+            PieHeaderError::EntryPointOutOfBounds { .. } => "PIE entry point out of bounds",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(ro_length: u32, code_length: u32) -> Vec<u8> {
+        let mut image = PieHeader::new(ro_length, code_length).to_bytes();
+        image.resize(PIE_HEADER_LENGTH + (ro_length + code_length) as usize, 0);
+        image
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = PieHeader::new(6, 8);
+        let parsed = PieHeader::parse(&image(6, 8)).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(parsed.ro_range(), 64..70);
+        assert_eq!(parsed.code_range(), 70..78);
+        assert_eq!(parsed.entry_point, 70);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut bytes = image(0, 4);
+        bytes[5] = 9;
+        assert_eq!(
+            PieHeader::parse(&bytes),
+            Err(PieHeaderError::UnsupportedVersion { version: 9 })
+        );
+    }
+
+    #[test]
+    fn test_sections_out_of_bounds() {
+        let mut bytes = image(0, 4);
+        bytes.truncate(66);
+        assert_eq!(
+            PieHeader::parse(&bytes),
+            Err(PieHeaderError::SectionOutOfBounds { section: "code" })
+        );
+        assert_eq!(
+            PieHeader::parse(&[45, 50, 49, 45]),
+            Err(PieHeaderError::Truncated { length: 4 })
+        );
+    }
+
+    #[test]
+    fn test_entry_point_outside_code() {
+        let mut header = PieHeader::new(4, 4);
+        header.entry_point = 64;
+        let mut bytes = header.to_bytes();
+        bytes.resize(72, 0);
+        assert_eq!(
+            PieHeader::parse(&bytes),
+            Err(PieHeaderError::EntryPointOutOfBounds { entry_point: 64 })
+        );
+    }
+}
@@ -1,9 +1,11 @@
-use nom::do_parse;
-use nom::many1;
-use nom::named;
 use nom::types::CompleteStr;
+use nom::{alt, Context, Err, ErrorKind, IResult};
 
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::comment_parsers::comment;
+use crate::assembler::directive_parsers::directive;
 use crate::assembler::instruction_parsers::{instruction, AssemblerInstruction};
+use crate::assembler::span::Span;
 use crate::assembler::SymbolTable;
 
 #[derive(Debug, PartialEq)]
@@ -12,34 +14,94 @@ pub struct Program {
 }
 
 impl Program {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    /// Encodes every instruction, collecting the errors of all of those that
+    /// cannot be encoded
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, Vec<AssemblerError>> {
         let mut program = vec![];
+        let mut errors = vec![];
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes(symbols));
+            let offset = program.len() as u32;
+            match instruction.to_bytes(symbols, offset) {
+                Ok(mut bytes) => program.append(&mut bytes),
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
         }
-        program
     }
 }
 
-named!(pub program<CompleteStr, Program>,
-  do_parse!(
-    instructions: many1!(instruction) >>
-    (
-      Program {
-        instructions
-      }
-    )
-  )
-);
+/// Parses one or more instructions and directives, giving each the span it
+/// covers in `input`. A comment on the line of an instruction is attached to
+/// it and a line holding only a comment becomes an instruction of its own, so
+/// the comments survive parsing. Stops at the first line that does not parse,
+/// which is left in the remainder, unless a parser failed outright, e.g. on a
+/// malformed integer literal.
+pub fn program(input: CompleteStr) -> IResult<CompleteStr, Program> {
+    let mut instructions = vec![];
+    let mut rest = input;
+    loop {
+        let start = CompleteStr(rest.trim_start());
+        if start.is_empty() {
+            rest = start;
+            break;
+        }
+        let offset = |s: CompleteStr| input.len() - s.len();
+        if let Ok((remaining, c)) = comment(start) {
+            instructions.push(AssemblerInstruction {
+                opcode: None,
+                label: None,
+                directive: None,
+                operand1: None,
+                operand2: None,
+                operand3: None,
+                comment: Some(c),
+                span: Span::new("", &input, offset(start), offset(remaining)),
+            });
+            rest = remaining;
+            continue;
+        }
+        match alt!(start, instruction | directive) {
+            Ok((mut remaining, mut i)) => {
+                i.span = Span::new("", &input, offset(start), offset(remaining));
+                // The parsers skip trailing whitespace, newlines included, so
+                // only take the comment if it is still on the same line
+                let consumed = &input[offset(start)..offset(remaining)];
+                let same_line = !consumed[consumed.trim_end().len()..].contains('\n');
+                if same_line {
+                    if let Ok((after, c)) = comment(remaining) {
+                        i.comment = Some(c);
+                        remaining = after;
+                    }
+                }
+                instructions.push(i);
+                rest = remaining;
+            }
+            Err(Err::Failure(context)) => return Err(Err::Failure(context)),
+            Err(_) => {
+                rest = start;
+                break;
+            }
+        }
+    }
+    if instructions.is_empty() {
+        return Err(Err::Error(Context::Code(input, ErrorKind::Many1)));
+    }
+    Ok((rest, Program { instructions }))
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::Token;
 
     #[test]
     fn test_parse_program() {
         let result = program(CompleteStr("load $0 #100\n"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (leftover, p) = result.unwrap();
         assert_eq!(leftover, CompleteStr(""));
         assert_eq!(1, p.instructions.len());
@@ -49,6 +111,83 @@ mod tests {
     fn test_complete_program() {
         let test_program = CompleteStr(".data\nhello: .asciiz 'Hello everyone!'\n.code\nhlt");
         let result = program(test_program);
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_ok());
+        let (leftover, p) = result.unwrap();
+        assert_eq!(leftover, CompleteStr(""));
+        assert_eq!(4, p.instructions.len());
+    }
+
+    #[test]
+    fn test_program_spans() {
+        let result = program(CompleteStr(".code\n  load $0 #100\nhlt\n"));
+        let (_, p) = result.unwrap();
+        let span = &p.instructions[1].span;
+        assert_eq!((span.line, span.column, span.length), (2, 3, 12));
+        assert_eq!(&*span.text, "  load $0 #100");
+        assert_eq!(p.instructions[2].span.line, 3);
+    }
+
+    #[test]
+    fn test_to_bytes_rejects_out_of_range_literals() {
+        let (_, p) = program(CompleteStr(
+            "lui $0 #70000\nsetmb $0 $1 #300\ncall #99999\n",
+        ))
+        .unwrap();
+        let errors = p.to_bytes(&SymbolTable::new()).unwrap_err();
+        let values: Vec<i32> = errors
+            .iter()
+            .map(|e| match e {
+                AssemblerError::LiteralOutOfRange { value, .. } => *value,
+                e => panic!("unexpected error {:?}", e),
+            })
+            .collect();
+        assert_eq!(values, vec![70000, 300, 99999]);
+    }
+
+    #[test]
+    fn test_program_stops_at_bad_line() {
+        let (rest, p) = program(CompleteStr(".code\nhlt\n%%%\nhlt\n")).unwrap();
+        assert_eq!(2, p.instructions.len());
+        assert_eq!(rest, CompleteStr("%%%\nhlt\n"));
+        assert!(program(CompleteStr("  \n")).is_err());
+    }
+
+    #[test]
+    fn test_program_comments() {
+        let source =
+            "; header\n\n.code\n\nload $0 #1 ; trailing\n/* block\n   comment */\nhlt;\n; last";
+        let (rest, p) = program(CompleteStr(source)).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        let comments: Vec<Option<&str>> = p
+            .instructions
+            .iter()
+            .map(|i| match i.comment {
+                Some(Token::Comment { ref text }) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            comments,
+            vec![
+                Some("; header"),
+                None,
+                Some("; trailing"),
+                Some("/* block\n   comment */"),
+                Some(";"),
+                Some("; last"),
+            ]
+        );
+        assert!(p.instructions[0].is_comment());
+        assert!(!p.instructions[2].is_comment());
+        let span = &p.instructions[2].span;
+        assert_eq!((span.line, span.column, span.length), (5, 1, 10));
+    }
+
+    #[test]
+    fn test_comment_on_next_line_is_not_trailing() {
+        let (_, p) = program(CompleteStr("hlt\n; next\n")).unwrap();
+        assert_eq!(2, p.instructions.len());
+        assert_eq!(p.instructions[0].comment, None);
+        assert!(p.instructions[1].is_comment());
     }
 }
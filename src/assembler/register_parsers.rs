@@ -7,12 +7,41 @@ named!(pub register <CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("$") >>
-            reg_num: digit >>
+            reg_num: map_res!(digit, |d: CompleteStr| d.parse::<u8>()) >>
             (
-                Token::Register{
-                  reg_num: reg_num.parse::<u8>().unwrap()
-                }
+                Token::Register{ reg_num }
             )
         )
     )
 );
+
+named!(pub float_register <CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("$f") >>
+            reg_num: map_res!(digit, |d: CompleteStr| d.parse::<u8>()) >>
+            (
+                Token::FloatRegister{ reg_num }
+            )
+        )
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_register() {
+        let result = register(CompleteStr("$12"));
+        assert_eq!(result.unwrap().1, Token::Register { reg_num: 12 });
+        assert!(register(CompleteStr("$f1")).is_err());
+    }
+
+    #[test]
+    fn test_parse_float_register() {
+        let result = float_register(CompleteStr("$f3"));
+        assert_eq!(result.unwrap().1, Token::FloatRegister { reg_num: 3 });
+        assert!(float_register(CompleteStr("$3")).is_err());
+    }
+}
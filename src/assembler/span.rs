@@ -0,0 +1,121 @@
+/// Where a piece of assembly came from: a 1-based line and column in `file`,
+/// the number of characters it covers and the full text of that line. Code
+/// produced by a macro also records the invocation it was expanded from.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+    /// Boxed rather than a `String` to keep errors that carry spans small
+    pub text: Box<str>,
+    pub expanded_from: Option<Box<Span>>,
+}
+
+impl Span {
+    /// Span of the bytes `start..end` of `source`, clipped to the line `start`
+    /// is on and without trailing whitespace
+    pub fn new(file: &str, source: &str, start: usize, end: usize) -> Span {
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        Span {
+            file: file.to_string(),
+            line: source[..start].matches('\n').count() + 1,
+            column: source[line_start..start].chars().count() + 1,
+            length: source[start..end.min(line_end)].trim_end().chars().count(),
+            text: source[line_start..line_end].trim_end().into(),
+            expanded_from: None,
+        }
+    }
+
+    /// The text the span covers
+    pub fn covered(&self) -> &str {
+        let start = self
+            .text
+            .char_indices()
+            .nth(self.column.saturating_sub(1))
+            .map_or(self.text.len(), |(i, _)| i);
+        let end = self.text[start..]
+            .char_indices()
+            .nth(self.length)
+            .map_or(self.text.len(), |(i, _)| start + i);
+        &self.text[start..end]
+    }
+
+    /// Narrows the span to the first occurrence of `needle` inside it, or
+    /// returns it unchanged if `needle` is not there
+    pub fn find(&self, needle: &str) -> Span {
+        let covered = self.covered();
+        match covered.find(needle) {
+            Some(index) => Span {
+                column: self.column + covered[..index].chars().count(),
+                length: needle.chars().count(),
+                ..self.clone()
+            },
+            None => self.clone(),
+        }
+    }
+
+    /// Renders the source line with the span underlined, in the style of
+    /// `file:line:column` diagnostics
+    pub fn render(&self) -> String {
+        let gutter = " ".repeat(self.line.to_string().len());
+        format!(
+            "{gutter}--> {}:{}:{}\n{gutter} |\n{} | {}\n{gutter} | {}{}",
+            self.file,
+            self.line,
+            self.column,
+            self.line,
+            self.text,
+            " ".repeat(self.column - 1),
+            "^".repeat(self.length.max(1)),
+            gutter = gutter
+        )
+    }
+
+    /// Renders the chain of macro invocations the span was expanded from,
+    /// innermost first, as notes
+    pub fn render_expansions(&self) -> String {
+        let mut rendered = String::new();
+        let mut invocation = self.expanded_from.as_deref();
+        while let Some(span) = invocation {
+            rendered.push_str(&format!(
+                "note: in the macro invoked here\n{}\n",
+                span.render()
+            ));
+            invocation = span.expanded_from.as_deref();
+        }
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_position() {
+        let source = ".code\n  load $0 #1\nhlt\n";
+        let start = source.find("load").unwrap();
+        let span = Span::new("test.iasm", source, start, start + 12);
+        assert_eq!(span.line, 2);
+        assert_eq!(span.column, 3);
+        assert_eq!(span.length, 10);
+        assert_eq!(&*span.text, "  load $0 #1");
+        assert_eq!(span.covered(), "load $0 #1");
+    }
+
+    #[test]
+    fn test_span_find_and_render() {
+        let source = "load $0 #70000\n";
+        let span = Span::new("test.iasm", source, 0, source.len()).find("#70000");
+        assert_eq!(span.column, 9);
+        assert_eq!(span.length, 6);
+        assert_eq!(
+            span.render(),
+            " --> test.iasm:1:9\n  |\n1 | load $0 #70000\n  |         ^^^^^^"
+        );
+    }
+}
@@ -1,8 +1,11 @@
+use crate::assembler::span::Span;
+
 #[derive(Debug)]
 pub struct Symbol {
     name: String,
     offset: Option<u32>,
     symbol_type: SymbolType,
+    span: Option<Span>,
 }
 
 impl Symbol {
@@ -11,6 +14,7 @@ impl Symbol {
             name,
             symbol_type,
             offset: None,
+            span: None,
         }
     }
 
@@ -19,13 +23,35 @@ impl Symbol {
             name,
             symbol_type,
             offset: Some(offset),
+            span: None,
         }
     }
+
+    /// Records where the symbol was declared
+    pub fn with_span(mut self, span: Span) -> Symbol {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
+
+    pub fn span(&self) -> Option<&Span> {
+        self.span.as_ref()
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum SymbolType {
+    /// Address of an instruction in the PIE image
     Label,
+    /// Offset of a constant in the read-only section
+    Data,
 }
 
 #[derive(Debug)]
@@ -33,6 +59,12 @@ pub struct SymbolTable {
     pub symbols: Vec<Symbol>,
 }
 
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SymbolTable {
     pub fn new() -> SymbolTable {
         SymbolTable { symbols: vec![] }
@@ -51,6 +83,10 @@ impl SymbolTable {
         false
     }
 
+    pub fn symbol(&self, s: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == s)
+    }
+
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
         for symbol in &self.symbols {
             if symbol.name == s {
@@ -60,6 +96,21 @@ impl SymbolTable {
         None
     }
 
+    /// The declared symbol closest in spelling to `s`, if any is close enough
+    /// to be a likely typo
+    pub fn suggest(&self, s: &str) -> Option<&str> {
+        closest(s, self.symbols.iter().map(|symbol| symbol.name.as_str()))
+    }
+
+    /// Shifts the offsets of every symbol of `symbol_type` by `amount`
+    pub fn rebase(&mut self, symbol_type: SymbolType, amount: u32) {
+        for symbol in &mut self.symbols {
+            if symbol.symbol_type == symbol_type {
+                symbol.offset = symbol.offset.map(|offset| offset + amount);
+            }
+        }
+    }
+
     pub fn set_symbol_offset(&mut self, s: &str, offset: u32) -> bool {
         for symbol in &mut self.symbols {
             if symbol.name == s {
@@ -70,3 +121,49 @@ impl SymbolTable {
         false
     }
 }
+
+/// The candidate closest in spelling to `name`, if any is close enough to be
+/// a likely typo
+pub fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .map(|candidate| (edit_distance(candidate, name), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggest() {
+        let mut symbols = SymbolTable::new();
+        for name in &["loop", "done", "hello"] {
+            symbols.add_symbol(Symbol::new_with_offset(
+                name.to_string(),
+                SymbolType::Label,
+                0,
+            ));
+        }
+        assert_eq!(symbols.suggest("lop"), Some("loop"));
+        assert_eq!(symbols.suggest("helo"), Some("hello"));
+        assert_eq!(symbols.suggest("xyz"), None);
+    }
+}
@@ -0,0 +1,53 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::assembler::pie_header::PieHeaderError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisassemblerError {
+    BadHeader { error: PieHeaderError },
+    IllegalOpcode { address: usize, byte: u8 },
+    InvalidRegister { address: usize, register: u8 },
+    TruncatedInstruction { address: usize },
+    UnrepresentableFloat { address: usize, value: f64 },
+}
+
+impl fmt::Display for DisassemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisassemblerError::BadHeader { ref error } => {
+                write!(f, "Invalid PIE header: {}", error)
+            }
+            DisassemblerError::IllegalOpcode { address, byte } => {
+                write!(f, "Illegal opcode {} at {}", byte, address)
+            }
+            DisassemblerError::InvalidRegister { address, register } => {
+                write!(f, "Invalid register ${} at {}", register, address)
+            }
+            DisassemblerError::TruncatedInstruction { address } => {
+                write!(
+                    f,
+                    "The instruction at {} runs past the code section",
+                    address
+                )
+            }
+            DisassemblerError::UnrepresentableFloat { address, value } => write!(
+                f,
+                "The float {} loaded at {} cannot be written as a literal",
+                value, address
+            ),
+        }
+    }
+}
+
+impl Error for DisassemblerError {
+    fn description(&self) -> &str {
+        match self {
+            DisassemblerError::BadHeader { .. } => "Invalid PIE header",
+            DisassemblerError::IllegalOpcode { .. } => "Illegal opcode",
+            DisassemblerError::InvalidRegister { .. } => "Invalid register",
+            DisassemblerError::TruncatedInstruction { .. } => "Truncated instruction",
+            DisassemblerError::UnrepresentableFloat { .. } => "Unrepresentable float literal",
+        }
+    }
+}
@@ -0,0 +1,305 @@
+pub mod disassembler_errors;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::assembler::operand_parsers::{float_literal, string_literal};
+use crate::assembler::pie_header::PieHeader;
+use crate::disassembler::disassembler_errors::DisassemblerError;
+use crate::instruction::{Opcode, OperandKind, REGISTER_COUNT};
+
+/// Bytes listed on each `.byte` line of read-only data that is not made of
+/// strings
+const BYTES_PER_ROW: usize = 16;
+
+#[derive(Debug, PartialEq)]
+enum Operand {
+    Register(u8),
+    FloatRegister(u8),
+    Byte(u8),
+    Half(u16),
+    Displacement(u16),
+    Float(f64),
+}
+
+#[derive(Debug, PartialEq)]
+struct DecodedInstruction {
+    address: usize,
+    opcode: Opcode,
+    operands: Vec<Operand>,
+}
+
+impl DecodedInstruction {
+    /// Address a call or relative jump transfers control to
+    fn target(&self) -> Option<usize> {
+        match (self.opcode, self.operands.first()) {
+            (Opcode::CALL, Some(Operand::Half(address))) => Some(*address as usize),
+            (Opcode::JMPF, Some(Operand::Displacement(d))) => Some(self.address + *d as usize),
+            (Opcode::JMPB, Some(Operand::Displacement(d))) => self.address.checked_sub(*d as usize),
+            (Opcode::JNEQ, Some(Operand::Displacement(d)))
+            | (Opcode::JZ, Some(Operand::Displacement(d))) => {
+                let target = self.address as i64 + i64::from(*d as i16);
+                if target < 0 {
+                    None
+                } else {
+                    Some(target as usize)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Turns a PIE image back into assembly source. Every string in the read-only
+/// section is given a `strN` label and every call or relative jump target a
+/// `labelN` label, so an image laid out by the assembler reassembles to the
+/// same bytes. A read-only section that is not made of strings is written out
+/// as `.byte` directives. The entry point is not preserved; the output always starts at
+/// its first instruction.
+pub fn disassemble(image: &[u8]) -> Result<String, DisassemblerError> {
+    let header = PieHeader::parse(image).map_err(|error| DisassemblerError::BadHeader { error })?;
+    let ro = &image[header.ro_range()];
+    let strings = split_strings(ro).unwrap_or_default();
+    let instructions = decode(&image[header.code_range()], header.code_offset as usize)?;
+
+    let starts: BTreeSet<usize> = instructions.iter().map(|i| i.address).collect();
+    let labels: BTreeMap<usize, String> = instructions
+        .iter()
+        .filter_map(DecodedInstruction::target)
+        .filter(|target| starts.contains(target))
+        .collect::<BTreeSet<usize>>()
+        .into_iter()
+        .enumerate()
+        .map(|(index, target)| (target, format!("label{}", index)))
+        .collect();
+    let string_labels: BTreeMap<usize, String> = strings
+        .iter()
+        .enumerate()
+        .map(|(index, (offset, _))| (*offset, format!("str{}", index)))
+        .collect();
+
+    let mut source = String::from(".data\n");
+    for (offset, string) in &strings {
+        source.push_str(&format!(
+            "{}: .asciiz {}\n",
+            string_labels[offset],
+            string_literal(string)
+        ));
+    }
+    if strings.is_empty() {
+        for chunk in ro.chunks(BYTES_PER_ROW) {
+            let bytes: Vec<String> = chunk.iter().map(|b| format!("#{}", b)).collect();
+            source.push_str(&format!(".byte {}\n", bytes.join(" ")));
+        }
+    }
+    source.push_str(".code\n");
+    for instruction in &instructions {
+        if let Some(label) = labels.get(&instruction.address) {
+            source.push_str(label);
+            source.push_str(": ");
+        }
+        source.push_str(&instruction.opcode.mnemonic());
+        for operand in &instruction.operands {
+            source.push(' ');
+            source.push_str(&render_operand(
+                instruction,
+                operand,
+                &labels,
+                &string_labels,
+            )?);
+        }
+        source.push('\n');
+    }
+    Ok(source)
+}
+
+/// Splits the read-only section into its NUL-terminated strings, keyed by
+/// offset, or `None` if it holds anything else
+fn split_strings(ro: &[u8]) -> Option<Vec<(usize, String)>> {
+    let mut strings = vec![];
+    let mut offset = 0;
+    while offset < ro.len() {
+        let length = ro[offset..].iter().position(|&b| b == 0)?;
+        let string = std::str::from_utf8(&ro[offset..offset + length]).ok()?;
+        strings.push((offset, string.to_string()));
+        offset += length + 1;
+    }
+    Some(strings)
+}
+
+/// Decodes the code section, which starts at `base` in the image
+fn decode(code: &[u8], base: usize) -> Result<Vec<DecodedInstruction>, DisassemblerError> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < code.len() {
+        let address = base + offset;
+        let opcode = Opcode::from(code[offset]);
+        if opcode == Opcode::IGL {
+            return Err(DisassemblerError::IllegalOpcode {
+                address,
+                byte: code[offset],
+            });
+        }
+        let length = if opcode == Opcode::LOADF { 12 } else { 4 };
+        let bytes = code
+            .get(offset..offset + length)
+            .ok_or(DisassemblerError::TruncatedInstruction { address })?;
+
+        let mut operands = vec![];
+        let mut cursor = 1;
+        for kind in opcode.operands() {
+            let operand = match kind {
+                OperandKind::Register | OperandKind::FloatRegister => {
+                    let register = bytes[cursor];
+                    cursor += 1;
+                    if register >= REGISTER_COUNT {
+                        return Err(DisassemblerError::InvalidRegister { address, register });
+                    }
+                    if *kind == OperandKind::Register {
+                        Operand::Register(register)
+                    } else {
+                        Operand::FloatRegister(register)
+                    }
+                }
+                OperandKind::Byte => {
+                    cursor += 1;
+                    Operand::Byte(bytes[cursor - 1])
+                }
+                OperandKind::Half | OperandKind::Displacement => {
+                    let value = BigEndian::read_u16(&bytes[cursor..cursor + 2]);
+                    cursor += 2;
+                    if *kind == OperandKind::Half {
+                        Operand::Half(value)
+                    } else {
+                        Operand::Displacement(value)
+                    }
+                }
+                OperandKind::Float => Operand::Float(BigEndian::read_f64(&bytes[4..12])),
+            };
+            operands.push(operand);
+        }
+        instructions.push(DecodedInstruction {
+            address,
+            opcode,
+            operands,
+        });
+        offset += length;
+    }
+    Ok(instructions)
+}
+
+fn render_operand(
+    instruction: &DecodedInstruction,
+    operand: &Operand,
+    labels: &BTreeMap<usize, String>,
+    string_labels: &BTreeMap<usize, String>,
+) -> Result<String, DisassemblerError> {
+    let label = match instruction.opcode {
+        Opcode::PRTS => match operand {
+            Operand::Half(offset) => string_labels.get(&(*offset as usize)),
+            _ => None,
+        },
+        _ => instruction.target().and_then(|target| labels.get(&target)),
+    };
+    if let Some(label) = label {
+        return Ok(format!("@{}", label));
+    }
+    Ok(match *operand {
+        Operand::Register(register) => format!("${}", register),
+        Operand::FloatRegister(register) => format!("$f{}", register),
+        Operand::Byte(value) => format!("#{}", value),
+        Operand::Half(value) => format!("#{}", value),
+        // Only the conditional jumps take a signed displacement
+        Operand::Displacement(value) if matches!(instruction.opcode, Opcode::JNEQ | Opcode::JZ) => {
+            format!("#{}", value as i16)
+        }
+        Operand::Displacement(value) => format!("#{}", value),
+        Operand::Float(value) if value.is_finite() => float_literal(value),
+        Operand::Float(value) => {
+            return Err(DisassemblerError::UnrepresentableFloat {
+                address: instruction.address,
+                value,
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn assert_round_trip(source: &str) -> String {
+        let original = Assembler::new().assemble(source).unwrap();
+        let disassembled = disassemble(&original).unwrap();
+        let reassembled = Assembler::new().assemble(&disassembled).unwrap();
+        assert_eq!(original, reassembled, "{}", disassembled);
+        disassembled
+    }
+
+    #[test]
+    fn test_disassemble_strings_and_prts() {
+        let disassembled = assert_round_trip(
+            ".data\nhello: .asciiz 'Hello'\nworld: .asciiz 'World'\n.code\nprts @world\nhlt\n",
+        );
+        assert_eq!(
+            disassembled,
+            ".data\nstr0: .asciiz 'Hello'\nstr1: .asciiz 'World'\n.code\nprts @str1\nhlt\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_escapes_strings() {
+        let disassembled = assert_round_trip(
+            ".data\nline: .asciiz \"it's \\\"done\\\"\\n\\ttab\\x7f\\u{85}\\\\\"\n.code\nprts @line\nhlt\n",
+        );
+        assert_eq!(
+            disassembled,
+            ".data\nstr0: .asciiz \"it's \\\"done\\\"\\n\\ttab\\x7f\\u{85}\\\\\"\n.code\nprts @str0\nhlt\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_raw_data() {
+        let disassembled =
+            assert_round_trip(".data\nw: .word #-2\nmsg: .asciiz 'hi'\n.code\nprts @msg\nhlt\n");
+        assert_eq!(
+            disassembled,
+            ".data\n.byte #255 #255 #255 #254 #104 #105 #0\n.code\nprts #4\nhlt\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_synthesizes_jump_labels() {
+        let disassembled = assert_round_trip(
+            ".data\n.code\nstart: load $0 #1\nloop: add $0 $0 $0\njz @start\njmpb @loop\ncall @done\ndone: hlt\n",
+        );
+        assert_eq!(
+            disassembled,
+            ".data\n.code\nlabel0: load $0 #1\nlabel1: add $0 $0 $0\njz @label0\njmpb @label1\ncall @label2\nlabel2: hlt\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_every_operand_kind() {
+        assert_round_trip(
+            ".data\n.code\nload $1 #-70000\nloadf $f2 #-1.5\nloadf $f3 #2.0\nitof $1 $f4\nftoi $f4 $5\nsetmw $1 $2 #255\nnot $1 $2\npush $3\njmpf @end\nnop\nend: hlt\n",
+        );
+    }
+
+    #[test]
+    fn test_disassemble_errors() {
+        let mut image = Assembler::new().assemble(".data\n.code\nhlt\n").unwrap();
+        let code_start = image.len() - 4;
+        image[code_start] = 5;
+        assert_eq!(
+            disassemble(&image),
+            Err(DisassemblerError::IllegalOpcode {
+                address: code_start,
+                byte: 5
+            })
+        );
+        assert!(disassemble(&[1, 2, 3]).is_err());
+    }
+}
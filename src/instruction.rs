@@ -1,25 +1,66 @@
 use nom::types::CompleteStr;
 
+/// Size of the integer and float register banks
+pub const REGISTER_COUNT: u8 = 32;
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Opcode {
-    LOAD,
-    ADD,
-    SUB,
-    MUL,
-    DIV,
-    HLT,
-    JMP,
-    EQ,
-    NEQ,
-    GT,
-    GTE,
-    LT,
-    LTE,
-    JMPE,
-    NOP,
-    ALOC,
-    IGL,
-    PRTS,
+    LOAD = 0,
+    ADD = 1,
+    SUB = 2,
+    MUL = 3,
+    DIV = 4,
+    HLT = 6,
+    JMP = 7,
+    EQ = 8,
+    NEQ = 9,
+    GT = 10,
+    GTE = 11,
+    LT = 12,
+    LTE = 13,
+    JMPE = 14,
+    NOP = 15,
+    ALOC = 16,
+    IGL = 255,
+    PRTS = 17,
+    CALL = 18,
+    RET = 19,
+    PUSH = 20,
+    POP = 21,
+    LOADMB = 22,
+    LOADMH = 23,
+    LOADMW = 24,
+    SETMB = 25,
+    SETMH = 26,
+    SETMW = 27,
+    JC = 28,
+    JO = 29,
+    JN = 30,
+    LUI = 31,
+    AND = 32,
+    OR = 33,
+    XOR = 34,
+    NOT = 35,
+    SHL = 36,
+    SHR = 37,
+    SAR = 38,
+    LOADF = 39,
+    ADDF = 40,
+    SUBF = 41,
+    MULF = 42,
+    DIVF = 43,
+    EQF = 44,
+    NEQF = 45,
+    GTF = 46,
+    GTEF = 47,
+    LTF = 48,
+    LTEF = 49,
+    ITOF = 50,
+    FTOI = 51,
+    JMPF = 52,
+    JMPB = 53,
+    JNEQ = 54,
+    JZ = 55,
 }
 
 impl From<u8> for Opcode {
@@ -42,6 +83,44 @@ impl From<u8> for Opcode {
             15 => Opcode::NOP,
             16 => Opcode::ALOC,
             17 => Opcode::PRTS,
+            18 => Opcode::CALL,
+            19 => Opcode::RET,
+            20 => Opcode::PUSH,
+            21 => Opcode::POP,
+            22 => Opcode::LOADMB,
+            23 => Opcode::LOADMH,
+            24 => Opcode::LOADMW,
+            25 => Opcode::SETMB,
+            26 => Opcode::SETMH,
+            27 => Opcode::SETMW,
+            28 => Opcode::JC,
+            29 => Opcode::JO,
+            30 => Opcode::JN,
+            31 => Opcode::LUI,
+            32 => Opcode::AND,
+            33 => Opcode::OR,
+            34 => Opcode::XOR,
+            35 => Opcode::NOT,
+            36 => Opcode::SHL,
+            37 => Opcode::SHR,
+            38 => Opcode::SAR,
+            39 => Opcode::LOADF,
+            40 => Opcode::ADDF,
+            41 => Opcode::SUBF,
+            42 => Opcode::MULF,
+            43 => Opcode::DIVF,
+            44 => Opcode::EQF,
+            45 => Opcode::NEQF,
+            46 => Opcode::GTF,
+            47 => Opcode::GTEF,
+            48 => Opcode::LTF,
+            49 => Opcode::LTEF,
+            50 => Opcode::ITOF,
+            51 => Opcode::FTOI,
+            52 => Opcode::JMPF,
+            53 => Opcode::JMPB,
+            54 => Opcode::JNEQ,
+            55 => Opcode::JZ,
             _ => Opcode::IGL,
         }
     }
@@ -67,11 +146,176 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
             CompleteStr("nop") => Opcode::NOP,
             CompleteStr("aloc") => Opcode::ALOC,
             CompleteStr("prts") => Opcode::PRTS,
+            CompleteStr("call") => Opcode::CALL,
+            CompleteStr("ret") => Opcode::RET,
+            CompleteStr("push") => Opcode::PUSH,
+            CompleteStr("pop") => Opcode::POP,
+            CompleteStr("loadmb") => Opcode::LOADMB,
+            CompleteStr("loadmh") => Opcode::LOADMH,
+            CompleteStr("loadmw") => Opcode::LOADMW,
+            CompleteStr("setmb") => Opcode::SETMB,
+            CompleteStr("setmh") => Opcode::SETMH,
+            CompleteStr("setmw") => Opcode::SETMW,
+            CompleteStr("jc") => Opcode::JC,
+            CompleteStr("jo") => Opcode::JO,
+            CompleteStr("jn") => Opcode::JN,
+            CompleteStr("lui") => Opcode::LUI,
+            CompleteStr("and") => Opcode::AND,
+            CompleteStr("or") => Opcode::OR,
+            CompleteStr("xor") => Opcode::XOR,
+            CompleteStr("not") => Opcode::NOT,
+            CompleteStr("shl") => Opcode::SHL,
+            CompleteStr("shr") => Opcode::SHR,
+            CompleteStr("sar") => Opcode::SAR,
+            CompleteStr("loadf") => Opcode::LOADF,
+            CompleteStr("addf") => Opcode::ADDF,
+            CompleteStr("subf") => Opcode::SUBF,
+            CompleteStr("mulf") => Opcode::MULF,
+            CompleteStr("divf") => Opcode::DIVF,
+            CompleteStr("eqf") => Opcode::EQF,
+            CompleteStr("neqf") => Opcode::NEQF,
+            CompleteStr("gtf") => Opcode::GTF,
+            CompleteStr("gtef") => Opcode::GTEF,
+            CompleteStr("ltf") => Opcode::LTF,
+            CompleteStr("ltef") => Opcode::LTEF,
+            CompleteStr("itof") => Opcode::ITOF,
+            CompleteStr("ftoi") => Opcode::FTOI,
+            CompleteStr("jmpf") => Opcode::JMPF,
+            CompleteStr("jmpb") => Opcode::JMPB,
+            CompleteStr("jneq") => Opcode::JNEQ,
+            CompleteStr("jz") => Opcode::JZ,
             _ => Opcode::IGL,
         }
     }
 }
 
+impl Opcode {
+    /// Heap load/store opcodes address memory as `$base + offset`, with the
+    /// offset packed into the last byte of the instruction
+    pub fn is_heap_access(self) -> bool {
+        matches!(
+            self,
+            Opcode::LOADMB
+                | Opcode::LOADMH
+                | Opcode::LOADMW
+                | Opcode::SETMB
+                | Opcode::SETMH
+                | Opcode::SETMW
+        )
+    }
+
+    /// Jumps whose 16-bit operand is a displacement from the jump itself
+    pub fn is_relative_jump(self) -> bool {
+        matches!(
+            self,
+            Opcode::JMPF | Opcode::JMPB | Opcode::JNEQ | Opcode::JZ
+        )
+    }
+
+    /// Mnemonic the assembler accepts for this opcode
+    pub fn mnemonic(self) -> String {
+        format!("{:?}", self).to_lowercase()
+    }
+
+    /// Mnemonics of every opcode
+    pub fn mnemonics() -> impl Iterator<Item = String> {
+        (0..=u8::MAX)
+            .map(Opcode::from)
+            .filter(|opcode| *opcode != Opcode::IGL)
+            .map(Opcode::mnemonic)
+    }
+
+    /// Operands in the order they follow the opcode byte. Instructions are
+    /// zero-padded to 4 bytes, except LOADF whose f64 takes two extra words.
+    pub fn operands(self) -> &'static [OperandKind] {
+        use self::OperandKind::*;
+        match self {
+            Opcode::LOAD | Opcode::LUI => &[Register, Half],
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::SAR => &[Register, Register, Register],
+            Opcode::NOT
+            | Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::GTE
+            | Opcode::LT
+            | Opcode::LTE => &[Register, Register],
+            Opcode::JMP
+            | Opcode::JMPE
+            | Opcode::JC
+            | Opcode::JO
+            | Opcode::JN
+            | Opcode::ALOC
+            | Opcode::PUSH
+            | Opcode::POP => &[Register],
+            Opcode::LOADMB
+            | Opcode::LOADMH
+            | Opcode::LOADMW
+            | Opcode::SETMB
+            | Opcode::SETMH
+            | Opcode::SETMW => &[Register, Register, Byte],
+            Opcode::PRTS | Opcode::CALL => &[Half],
+            Opcode::JMPF | Opcode::JMPB | Opcode::JNEQ | Opcode::JZ => &[Displacement],
+            Opcode::LOADF => &[FloatRegister, Float],
+            Opcode::ADDF | Opcode::SUBF | Opcode::MULF | Opcode::DIVF => {
+                &[FloatRegister, FloatRegister, FloatRegister]
+            }
+            Opcode::EQF
+            | Opcode::NEQF
+            | Opcode::GTF
+            | Opcode::GTEF
+            | Opcode::LTF
+            | Opcode::LTEF => &[FloatRegister, FloatRegister],
+            Opcode::ITOF => &[Register, FloatRegister],
+            Opcode::FTOI => &[FloatRegister, Register],
+            Opcode::HLT | Opcode::NOP | Opcode::RET => &[],
+            // Not an instruction at all; the assembler rejects it as an
+            // unknown mnemonic before looking at its operands
+            Opcode::IGL => &[],
+        }
+    }
+}
+
+/// How an operand is encoded in the bytes following its opcode
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OperandKind {
+    /// One byte naming an integer register
+    Register,
+    /// One byte naming a float register
+    FloatRegister,
+    /// One byte immediate
+    Byte,
+    /// Big-endian 16-bit immediate: a literal, a code address or a read-only
+    /// offset
+    Half,
+    /// Big-endian 16-bit jump displacement from the start of the instruction
+    Displacement,
+    /// Big-endian f64 in the two words following the instruction
+    Float,
+}
+
+impl OperandKind {
+    /// What the assembler accepts for this operand, for error messages
+    pub fn describe(self) -> &'static str {
+        match self {
+            OperandKind::Register => "a register",
+            OperandKind::FloatRegister => "a float register",
+            OperandKind::Byte => "an 8-bit literal",
+            OperandKind::Half => "a 16-bit literal or a label",
+            OperandKind::Displacement => "a label or a 16-bit displacement",
+            OperandKind::Float => "a float literal",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Instruction {
     opcode: Opcode,
@@ -79,10 +323,11 @@ pub struct Instruction {
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode: opcode }
+        Instruction { opcode }
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -97,4 +342,38 @@ mod tests {
         let instruction = Instruction::new(Opcode::HLT);
         assert_eq!(instruction.opcode, Opcode::HLT);
     }
+
+    #[test]
+    fn test_call_ret_round_trip() {
+        assert_eq!(Opcode::from(Opcode::CALL as u8), Opcode::CALL);
+        assert_eq!(Opcode::from(Opcode::RET as u8), Opcode::RET);
+        assert_eq!(Opcode::from(CompleteStr("call")), Opcode::CALL);
+        assert_eq!(Opcode::from(CompleteStr("ret")), Opcode::RET);
+    }
+
+    #[test]
+    fn test_mnemonic_round_trip() {
+        for byte in 0..=u8::MAX {
+            let opcode = Opcode::from(byte);
+            if opcode != Opcode::IGL {
+                assert_eq!(Opcode::from(CompleteStr(&opcode.mnemonic())), opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bitwise_mnemonics() {
+        for (mnemonic, opcode) in &[
+            ("and", Opcode::AND),
+            ("or", Opcode::OR),
+            ("xor", Opcode::XOR),
+            ("not", Opcode::NOT),
+            ("shl", Opcode::SHL),
+            ("shr", Opcode::SHR),
+            ("sar", Opcode::SAR),
+        ] {
+            assert_eq!(Opcode::from(CompleteStr(mnemonic)), *opcode);
+            assert_eq!(Opcode::from(*opcode as u8), *opcode);
+        }
+    }
 }
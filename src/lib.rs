@@ -0,0 +1,5 @@
+pub mod assembler;
+pub mod disassembler;
+pub mod instruction;
+pub mod repl;
+pub mod vm;
@@ -1,36 +1,47 @@
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
-
-use clap::App;
+use basalt::assembler::PIE_HEADER_PREFIX;
+use basalt::{assembler, disassembler, repl, vm};
+use clap::{App, ArgMatches};
 
 fn main() {
     let yaml = clap::load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
-    let target_file = matches.value_of("INPUT_FILE");
-    match target_file {
-        Some(filename) => {
-            let program = read_file(filename);
-            let mut asm = assembler::Assembler::new();
-            let mut vm = vm::VM::new();
-            let program = asm.assemble(&program);
-            match program {
-                Ok(p) => {
-                    vm.add_bytes(p);
-                    vm.run();
-                    std::process::exit(0);
+    match matches.subcommand() {
+        ("assemble", Some(sub_matches)) => {
+            let input = sub_matches.value_of("INPUT_FILE").unwrap();
+            let output = match sub_matches.value_of("OUTPUT_FILE") {
+                Some(output) => Path::new(output).to_path_buf(),
+                None => Path::new(input).with_extension("pie"),
+            };
+            let program = assemble_source(input, read_file(input), include_paths(sub_matches));
+            if let Err(e) = std::fs::write(&output, program) {
+                println!("There was an error writing {}: {:?}", output.display(), e);
+                std::process::exit(1);
+            }
+        }
+        ("disassemble", Some(sub_matches)) => {
+            let filename = sub_matches.value_of("INPUT_FILE").unwrap();
+            match disassembler::disassemble(&read_file(filename)) {
+                Ok(source) => print!("{}", source),
+                Err(e) => {
+                    println!("Unable to disassemble {}: {}", filename, e);
+                    std::process::exit(1);
                 }
-                Err(e) => println!("{:?}", e),
             }
         }
-        None => {
-            start_repl();
+        ("run", Some(sub_matches)) => {
+            run_file(
+                sub_matches.value_of("INPUT_FILE").unwrap(),
+                include_paths(sub_matches),
+            );
         }
+        _ => match matches.value_of("INPUT_FILE") {
+            Some(filename) => run_file(filename, vec![]),
+            None => start_repl(),
+        },
     }
 }
 
@@ -39,20 +50,70 @@ fn start_repl() {
     repl.run();
 }
 
-fn read_file(tmp: &str) -> String {
+/// Directories given with `--include-path`
+fn include_paths(matches: &ArgMatches) -> Vec<PathBuf> {
+    matches
+        .values_of("INCLUDE_PATH")
+        .map(|paths| paths.map(PathBuf::from).collect())
+        .unwrap_or_default()
+}
+
+/// Runs a PIE file as is, or assembles and runs it if it is source code
+fn run_file(filename: &str, include_paths: Vec<PathBuf>) {
+    let contents = read_file(filename);
+    let program = if contents.starts_with(&PIE_HEADER_PREFIX) {
+        contents
+    } else {
+        assemble_source(filename, contents, include_paths)
+    };
+    let mut vm = vm::VM::new();
+    vm.add_bytes(program);
+    match vm.run() {
+        Ok(_) => std::process::exit(0),
+        Err(e) => {
+            println!("The program stopped with an error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn assemble_source(filename: &str, contents: Vec<u8>, include_paths: Vec<PathBuf>) -> Vec<u8> {
+    let source = match String::from_utf8(contents) {
+        Ok(source) => source,
+        Err(e) => {
+            println!("{} is neither a PIE file nor UTF-8 source: {}", filename, e);
+            std::process::exit(1);
+        }
+    };
+    let mut asm = assembler::Assembler::new();
+    asm.include_paths = include_paths;
+    let result = asm.assemble_named(filename, &source);
+    for warning in &asm.warnings {
+        println!("{}", warning.render());
+    }
+    match result {
+        Ok(program) => program,
+        Err(errors) => {
+            for e in errors {
+                println!("{}", e.render());
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_file(tmp: &str) -> Vec<u8> {
     let filename = Path::new(tmp);
-    let mut f = match File::open(&filename) {
+    let mut f = match File::open(filename) {
         Ok(f) => f,
         Err(e) => {
             println!("There was an error opening that file: {:?}", e);
             std::process::exit(1);
         }
     };
-    let mut contents = String::new();
-    match f.read_to_string(&mut contents) {
-        Ok(_) => {
-            return contents;
-        }
+    let mut contents = vec![];
+    match f.read_to_end(&mut contents) {
+        Ok(_) => contents,
         Err(e) => {
             println!("There was an error reading file: {:?}", e);
             std::process::exit(1);
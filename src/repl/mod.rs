@@ -1,11 +1,16 @@
+use crate::assembler::assembler_errors::AssemblerError;
 use crate::assembler::program_parsers::program;
-use crate::vm::VM;
+use crate::disassembler::disassemble;
+use crate::vm::vm_errors::VmError;
+use crate::vm::{ExitReason, VM};
 use std;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::io::Write;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use crate::assembler::Assembler;
 
@@ -13,6 +18,15 @@ pub struct REPL {
     command_buffer: Vec<String>,
     vm: VM,
     asm: Assembler,
+    /// Set while the VM runs, so that Ctrl-C interrupts the program instead
+    /// of leaving the REPL
+    vm_running: Arc<AtomicBool>,
+}
+
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl REPL {
@@ -21,11 +35,26 @@ impl REPL {
             vm: VM::new(),
             command_buffer: vec![],
             asm: Assembler::new(),
+            vm_running: Arc::new(AtomicBool::new(false)),
         }
     }
 
     pub fn run(&mut self) {
         println!("Welcome!");
+        let interrupt = self.vm.interrupt_handle();
+        let vm_running = Arc::clone(&self.vm_running);
+        let handler = move || {
+            if vm_running.load(Ordering::SeqCst) {
+                interrupt.interrupt();
+            } else {
+                // At the prompt Ctrl-C leaves, as it would without a handler
+                println!();
+                std::process::exit(130);
+            }
+        };
+        if let Err(e) = ctrlc::set_handler(handler) {
+            println!("Unable to install the Ctrl-C handler: {}", e);
+        }
         loop {
             let mut buffer = String::new();
             let stdin = io::stdin();
@@ -55,9 +84,17 @@ impl REPL {
                         println!("{}", instruction);
                     }
                 }
+                ".disassemble" => match disassemble(&self.vm.program) {
+                    Ok(source) => print!("{}", source),
+                    Err(e) => println!("Unable to disassemble the program: {}", e),
+                },
                 "registers" => {
                     println!("print out registers list");
                     println!("{:#?}", self.vm.registers);
+                    println!("{:#?}", self.vm.float_registers);
+                }
+                ".resume" => {
+                    self.run_vm(VM::resume);
                 }
                 ".symbols" => {
                     println!("Listing symbols table:");
@@ -73,7 +110,7 @@ impl REPL {
                         .expect("Unable to read line from user");
                     let _path = path.trim();
                     let filename = Path::new(&_path);
-                    let mut file = match File::open(&filename) {
+                    let mut file = match File::open(filename) {
                         Ok(file) => file,
                         Err(e) => {
                             println!("Cannot open the file {:?}: ", e);
@@ -83,15 +120,22 @@ impl REPL {
                     let mut contents = String::new();
                     file.read_to_string(&mut contents)
                         .expect("There was an error reading from file");
-                    match self.asm.assemble(&contents) {
+                    // Macros, symbols and sections from an earlier load would
+                    // clash with the ones this file declares
+                    self.asm = Assembler::new();
+                    let result = self.asm.assemble_named(_path, &contents);
+                    for warning in self.asm.warnings.drain(..) {
+                        println!("{}", warning.render());
+                    }
+                    match result {
                         Ok(mut assembled_program) => {
                             self.vm.program.append(&mut assembled_program);
                             println!("{:#?}", self.vm.program);
-                            self.vm.run();
+                            self.run_vm(VM::run);
                         }
                         Err(errors) => {
                             for e in errors {
-                                println!("Unable to parse input: {}", e);
+                                println!("{}", e.render());
                             }
                             continue;
                         }
@@ -101,17 +145,54 @@ impl REPL {
                     let program = match program(buffer.into()) {
                         Ok((_remainder, program)) => program,
                         Err(e) => {
-                            println!("Unable to parse input: {:?}", e);
+                            let error = AssemblerError::from_parse_failure("<repl>", buffer, &e);
+                            println!("{}", error.render());
                             continue;
                         }
                     };
 
-                    self.vm
-                        .program
-                        .append(&mut program.to_bytes(&self.asm.symbols));
-                    self.vm.run_once();
+                    match program.to_bytes(&self.asm.symbols) {
+                        Ok(mut bytes) => self.vm.program.append(&mut bytes),
+                        Err(errors) => {
+                            for e in errors {
+                                println!("{}", e.render());
+                            }
+                            continue;
+                        }
+                    }
+                    if let Err(e) = self.vm.run_once() {
+                        println!("Instruction failed: {}", e);
+                    }
                 }
             }
         }
     }
+
+    /// Runs or resumes the VM with Ctrl-C interrupting it
+    fn run_vm(&mut self, run: fn(&mut VM) -> Result<ExitReason, VmError>) {
+        self.vm.interrupt_handle().clear();
+        self.vm_running.store(true, Ordering::SeqCst);
+        let result = run(&mut self.vm);
+        self.vm_running.store(false, Ordering::SeqCst);
+        self.report_exit(result);
+    }
+
+    fn report_exit(&self, result: Result<ExitReason, VmError>) {
+        match result {
+            Ok(ExitReason::Halted) => {}
+            Ok(ExitReason::OutOfFuel) => {
+                println!(
+                    "Out of fuel at {}; type .resume to continue",
+                    self.vm.counter
+                );
+            }
+            Ok(ExitReason::Interrupted) => {
+                println!(
+                    "Interrupted at {}; type .resume to continue",
+                    self.vm.counter
+                );
+            }
+            Err(e) => println!("Program stopped with an error: {}", e),
+        }
+    }
 }
@@ -0,0 +1,1563 @@
+pub mod vm_errors;
+
+use crate::assembler::pie_header::PieHeader;
+use crate::instruction::Opcode;
+use crate::vm::vm_errors::VmError;
+use byteorder::{BigEndian, ByteOrder};
+use chrono::prelude::*;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Maximum number of nested `CALL`s before the VM reports a stack overflow
+pub const MAX_CALL_DEPTH: usize = 1024;
+/// Number of 32-bit slots in the VM's stack segment
+pub const STACK_SIZE: usize = 4096;
+/// Largest the heap may grow to through `ALOC`, in bytes
+pub const HEAP_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Bits of `VM::flags`, updated by arithmetic and comparison opcodes
+pub const FLAG_CARRY: u8 = 0b0001;
+pub const FLAG_OVERFLOW: u8 = 0b0010;
+pub const FLAG_ZERO: u8 = 0b0100;
+pub const FLAG_NEGATIVE: u8 = 0b1000;
+
+/// How ADD, SUB, MUL and DIV handle a result that does not fit in an `i32`.
+/// Division by zero always traps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArithmeticMode {
+    Trap,
+    Wrap,
+    Saturate,
+}
+
+/// Why `VM::run` or `VM::resume` returned without an error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitReason {
+    Halted,
+    OutOfFuel,
+    Interrupted,
+}
+
+/// Cloneable handle that asks a running VM to stop at the next instruction
+/// boundary. It can be used from another thread or a signal handler.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Drops a pending request, e.g. one made while the VM was not running
+    pub fn clear(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+
+    fn take(&self) -> bool {
+        self.flag.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum VMEVentType {
+    Start,
+    Stop,
+    OutOfFuel,
+    Interrupted,
+    GracefulStop { code: u32 },
+    Crash { error: VmError },
+}
+
+#[derive(Clone, Debug)]
+pub struct VMEvent {
+    pub event: VMEVentType,
+    pub at: DateTime<Utc>,
+    pub application_id: Uuid,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackFrame {
+    pub return_address: usize,
+    pub frame_pointer: usize,
+}
+
+pub struct VM {
+    pub registers: [i32; 32],
+    pub float_registers: [f64; 32],
+    pub counter: usize,
+    pub program: Vec<u8>,
+    pub remainder: u32,
+    pub equal_flag: bool,
+    pub flags: u8,
+    pub arithmetic_mode: ArithmeticMode,
+    /// Instructions `run`/`resume` may still execute, or `None` for no limit
+    pub fuel: Option<u64>,
+    pub call_stack: Vec<StackFrame>,
+    pub stack: Vec<i32>,
+    pub sp: usize,
+    pub fp: usize,
+    heap: Vec<u8>,
+    ro_data: Vec<u8>,
+    current_pc: usize,
+    current_opcode: Option<Opcode>,
+    interrupt: InterruptHandle,
+    id: Uuid,
+    events: Vec<VMEvent>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> VM {
+        VM {
+            registers: [0; 32],
+            float_registers: [0.0; 32],
+            program: vec![],
+            counter: 0,
+            remainder: 0,
+            equal_flag: false,
+            flags: 0,
+            arithmetic_mode: ArithmeticMode::Trap,
+            fuel: None,
+            call_stack: vec![],
+            stack: vec![0; STACK_SIZE],
+            sp: 0,
+            fp: 0,
+            heap: vec![],
+            ro_data: vec![],
+            current_pc: 0,
+            current_opcode: None,
+            interrupt: InterruptHandle::default(),
+            id: Uuid::new_v4(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Runs the loaded PIE program from its first instruction until it halts,
+    /// faults or runs out of fuel
+    pub fn run(&mut self) -> Result<ExitReason, VmError> {
+        self.events.push(VMEvent {
+            event: VMEVentType::Start,
+            at: Utc::now(),
+            application_id: self.id,
+        });
+        match self.load_sections() {
+            Ok(code_start) => self.counter = code_start,
+            Err(e) => return Err(self.crash(e)),
+        }
+        self.resume()
+    }
+
+    /// Continues execution from the current counter, e.g. after refuelling a
+    /// VM that returned `ExitReason::OutOfFuel` or one that was interrupted.
+    /// Each instruction costs one unit of fuel.
+    pub fn resume(&mut self) -> Result<ExitReason, VmError> {
+        loop {
+            if self.interrupt.take() {
+                self.events.push(VMEvent {
+                    event: VMEVentType::Interrupted,
+                    at: Utc::now(),
+                    application_id: self.id,
+                });
+                return Ok(ExitReason::Interrupted);
+            }
+            match self.fuel {
+                Some(0) => {
+                    self.events.push(VMEvent {
+                        event: VMEVentType::OutOfFuel,
+                        at: Utc::now(),
+                        application_id: self.id,
+                    });
+                    return Ok(ExitReason::OutOfFuel);
+                }
+                Some(fuel) => self.fuel = Some(fuel - 1),
+                None => {}
+            }
+            match self.execute_instruction() {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => return Err(self.crash(e)),
+            }
+        }
+        self.events.push(VMEvent {
+            event: VMEVentType::GracefulStop { code: 0 },
+            at: Utc::now(),
+            application_id: self.id,
+        });
+        Ok(ExitReason::Halted)
+    }
+
+    /// Returns a handle that interrupts this VM's `run`/`resume` loop
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Executes a single instruction, returning whether it halted the VM. Does
+    /// not consume fuel.
+    pub fn run_once(&mut self) -> Result<bool, VmError> {
+        self.execute_instruction()
+    }
+
+    fn crash(&mut self, error: VmError) -> VmError {
+        self.events.push(VMEvent {
+            event: VMEVentType::Crash {
+                error: error.clone(),
+            },
+            at: Utc::now(),
+            application_id: self.id,
+        });
+        error
+    }
+
+    fn execute_instruction(&mut self) -> Result<bool, VmError> {
+        self.current_pc = self.counter;
+        self.current_opcode = None;
+        let opcode = self.decode_opcode()?;
+        self.current_opcode = Some(opcode);
+        match opcode {
+            Opcode::LOAD => {
+                let register = self.next_register()?;
+                let number = self.next_16_bits()? as u32;
+                self.registers[register] = number as i32;
+            }
+            Opcode::LUI => {
+                let register = self.next_register()?;
+                let upper = i32::from(self.next_16_bits()?) << 16;
+                self.registers[register] = upper | (self.registers[register] & 0xFFFF);
+            }
+            Opcode::ADD => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let target = self.next_register()?;
+                let (wrapped, overflow) = register1.overflowing_add(register2);
+                let carry = (register1 as u32).overflowing_add(register2 as u32).1;
+                self.registers[target] = self.arithmetic_result(
+                    wrapped,
+                    register1.saturating_add(register2),
+                    carry,
+                    overflow,
+                )?;
+            }
+            Opcode::SUB => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let target = self.next_register()?;
+                let (wrapped, overflow) = register1.overflowing_sub(register2);
+                let carry = (register1 as u32) < (register2 as u32);
+                self.registers[target] = self.arithmetic_result(
+                    wrapped,
+                    register1.saturating_sub(register2),
+                    carry,
+                    overflow,
+                )?;
+            }
+            Opcode::MUL => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let target = self.next_register()?;
+                let (wrapped, overflow) = register1.overflowing_mul(register2);
+                self.registers[target] = self.arithmetic_result(
+                    wrapped,
+                    register1.saturating_mul(register2),
+                    overflow,
+                    overflow,
+                )?;
+            }
+            Opcode::DIV => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let target = self.next_register()?;
+                if register2 == 0 {
+                    return Err(VmError::DivisionByZero {
+                        pc: self.current_pc,
+                        opcode,
+                    });
+                }
+                let (wrapped, overflow) = register1.overflowing_div(register2);
+                self.registers[target] = self.arithmetic_result(
+                    wrapped,
+                    register1.saturating_div(register2),
+                    false,
+                    overflow,
+                )?;
+                self.remainder = register1.wrapping_rem(register2) as u32;
+            }
+            Opcode::AND => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let target = self.next_register()?;
+                self.registers[target] = self.bitwise_result(register1 & register2);
+            }
+            Opcode::OR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let target = self.next_register()?;
+                self.registers[target] = self.bitwise_result(register1 | register2);
+            }
+            Opcode::XOR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let target = self.next_register()?;
+                self.registers[target] = self.bitwise_result(register1 ^ register2);
+            }
+            Opcode::NOT => {
+                let register = self.next_register_value()?;
+                let target = self.next_register()?;
+                self.next_8_bits()?;
+                self.registers[target] = self.bitwise_result(!register);
+            }
+            Opcode::SHL => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let target = self.next_register()?;
+                self.registers[target] =
+                    self.bitwise_result(register1.wrapping_shl(register2 as u32));
+            }
+            Opcode::SHR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let target = self.next_register()?;
+                self.registers[target] =
+                    self.bitwise_result((register1 as u32).wrapping_shr(register2 as u32) as i32);
+            }
+            Opcode::SAR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let target = self.next_register()?;
+                self.registers[target] =
+                    self.bitwise_result(register1.wrapping_shr(register2 as u32));
+            }
+            Opcode::LOADF => {
+                let register = self.next_float_register()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let high = u64::from(self.next_32_bits()?);
+                let low = u64::from(self.next_32_bits()?);
+                self.float_registers[register] = f64::from_bits((high << 32) | low);
+            }
+            Opcode::ADDF => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                let target = self.next_float_register()?;
+                self.float_registers[target] = register1 + register2;
+            }
+            Opcode::SUBF => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                let target = self.next_float_register()?;
+                self.float_registers[target] = register1 - register2;
+            }
+            Opcode::MULF => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                let target = self.next_float_register()?;
+                self.float_registers[target] = register1 * register2;
+            }
+            Opcode::DIVF => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                let target = self.next_float_register()?;
+                self.float_registers[target] = register1 / register2;
+            }
+            Opcode::EQF => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                self.equal_flag = register1 == register2;
+                self.next_8_bits()?;
+            }
+            Opcode::NEQF => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                self.equal_flag = register1 != register2;
+                self.next_8_bits()?;
+            }
+            Opcode::GTF => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                self.equal_flag = register1 > register2;
+                self.next_8_bits()?;
+            }
+            Opcode::GTEF => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                self.equal_flag = register1 >= register2;
+                self.next_8_bits()?;
+            }
+            Opcode::LTF => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                self.equal_flag = register1 < register2;
+                self.next_8_bits()?;
+            }
+            Opcode::LTEF => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                self.equal_flag = register1 <= register2;
+                self.next_8_bits()?;
+            }
+            Opcode::ITOF => {
+                let value = self.next_register_value()?;
+                let target = self.next_float_register()?;
+                self.next_8_bits()?;
+                self.float_registers[target] = f64::from(value);
+            }
+            Opcode::FTOI => {
+                let value = self.next_float_register_value()?;
+                let target = self.next_register()?;
+                self.next_8_bits()?;
+                self.registers[target] = value as i32;
+            }
+            Opcode::HLT => {
+                println!("HLT");
+                return Ok(true);
+            }
+            Opcode::IGL => {
+                return Err(VmError::IllegalOpcode {
+                    pc: self.current_pc,
+                    byte: self.program[self.current_pc],
+                });
+            }
+            Opcode::JMP => {
+                let target = self.next_register_value()?;
+                self.counter = target as usize;
+            }
+            Opcode::EQ => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 == register2;
+                self.compare(register1, register2);
+                self.next_8_bits()?;
+            }
+            Opcode::NEQ => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 != register2;
+                self.compare(register1, register2);
+                self.next_8_bits()?;
+            }
+            Opcode::GT => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 > register2;
+                self.compare(register1, register2);
+                self.next_8_bits()?;
+            }
+            Opcode::GTE => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 >= register2;
+                self.compare(register1, register2);
+                self.next_8_bits()?;
+            }
+            Opcode::LT => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 < register2;
+                self.compare(register1, register2);
+                self.next_8_bits()?;
+            }
+            Opcode::LTE => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 <= register2;
+                self.compare(register1, register2);
+                self.next_8_bits()?;
+            }
+            Opcode::JMPE => {
+                let target = self.next_register_value()?;
+                if self.equal_flag {
+                    self.counter = target as usize;
+                }
+            }
+            Opcode::JMPF => {
+                let displacement = self.next_16_bits()? as usize;
+                self.counter = self.current_pc + displacement;
+            }
+            Opcode::JMPB => {
+                let displacement = self.next_16_bits()? as usize;
+                self.counter =
+                    self.current_pc
+                        .checked_sub(displacement)
+                        .ok_or(VmError::PcOutOfBounds {
+                            pc: self.current_pc,
+                            opcode: Some(opcode),
+                        })?;
+            }
+            Opcode::JNEQ => {
+                let displacement = self.next_16_bits()? as i16;
+                self.next_8_bits()?;
+                if !self.equal_flag {
+                    self.relative_jump(displacement)?;
+                }
+            }
+            Opcode::JZ => {
+                let displacement = self.next_16_bits()? as i16;
+                self.next_8_bits()?;
+                if self.flags & FLAG_ZERO != 0 {
+                    self.relative_jump(displacement)?;
+                }
+            }
+            Opcode::JC => {
+                let target = self.next_register_value()?;
+                if self.flags & FLAG_CARRY != 0 {
+                    self.counter = target as usize;
+                }
+            }
+            Opcode::JO => {
+                let target = self.next_register_value()?;
+                if self.flags & FLAG_OVERFLOW != 0 {
+                    self.counter = target as usize;
+                }
+            }
+            Opcode::JN => {
+                let target = self.next_register_value()?;
+                if self.flags & FLAG_NEGATIVE != 0 {
+                    self.counter = target as usize;
+                }
+            }
+            Opcode::NOP => {
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+            }
+            Opcode::ALOC => {
+                let bytes = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let new_end = usize::try_from(bytes)
+                    .ok()
+                    .and_then(|bytes| self.heap.len().checked_add(bytes))
+                    .filter(|new_end| *new_end <= HEAP_LIMIT)
+                    .ok_or(VmError::InvalidAllocation {
+                        pc: self.current_pc,
+                        bytes,
+                    })?;
+                self.heap.resize(new_end, 0);
+            }
+            Opcode::LOADMB => {
+                let register = self.next_register()?;
+                let address = self.heap_address(1)?;
+                self.registers[register] = i32::from(self.heap[address]);
+            }
+            Opcode::LOADMH => {
+                let register = self.next_register()?;
+                let address = self.heap_address(2)?;
+                let value = BigEndian::read_u16(&self.heap[address..address + 2]);
+                self.registers[register] = i32::from(value);
+            }
+            Opcode::LOADMW => {
+                let register = self.next_register()?;
+                let address = self.heap_address(4)?;
+                self.registers[register] = BigEndian::read_i32(&self.heap[address..address + 4]);
+            }
+            Opcode::SETMB => {
+                let value = self.next_register_value()?;
+                let address = self.heap_address(1)?;
+                self.heap[address] = value as u8;
+            }
+            Opcode::SETMH => {
+                let value = self.next_register_value()?;
+                let address = self.heap_address(2)?;
+                BigEndian::write_u16(&mut self.heap[address..address + 2], value as u16);
+            }
+            Opcode::SETMW => {
+                let value = self.next_register_value()?;
+                let address = self.heap_address(4)?;
+                BigEndian::write_i32(&mut self.heap[address..address + 4], value);
+            }
+            Opcode::PRTS => {
+                let starting_offset = self.next_16_bits()? as usize;
+                self.next_8_bits()?;
+                let slice = self.ro_data.as_slice();
+                let ending_offset = slice
+                    .get(starting_offset..)
+                    .and_then(|rest| rest.iter().position(|&b| b == 0))
+                    .map(|len| starting_offset + len)
+                    .ok_or(VmError::RoDataOutOfBounds {
+                        pc: self.current_pc,
+                        offset: starting_offset,
+                    })?;
+
+                let result = std::str::from_utf8(&slice[starting_offset..ending_offset]);
+                match result {
+                    Ok(s) => {
+                        print!("{}", s);
+                    }
+                    Err(e) => println!("Error decoding string for prts instruction: {:#?}", e),
+                }
+            }
+            Opcode::CALL => {
+                let target = self.next_16_bits()? as usize;
+                self.next_8_bits()?;
+                if self.call_stack.len() >= MAX_CALL_DEPTH {
+                    return Err(VmError::CallStackOverflow {
+                        pc: self.current_pc,
+                    });
+                }
+                self.call_stack.push(StackFrame {
+                    return_address: self.counter,
+                    frame_pointer: self.fp,
+                });
+                self.fp = self.sp;
+                self.counter = target;
+            }
+            Opcode::RET => match self.call_stack.pop() {
+                Some(frame) => {
+                    self.sp = self.fp;
+                    self.fp = frame.frame_pointer;
+                    self.counter = frame.return_address;
+                }
+                None => {
+                    return Err(VmError::CallStackUnderflow {
+                        pc: self.current_pc,
+                    });
+                }
+            },
+            Opcode::PUSH => {
+                let value = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                if self.sp >= STACK_SIZE {
+                    return Err(VmError::StackOverflow {
+                        pc: self.current_pc,
+                    });
+                }
+                self.stack[self.sp] = value;
+                self.sp += 1;
+            }
+            Opcode::POP => {
+                let register = self.next_register()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                if self.sp == 0 {
+                    return Err(VmError::StackUnderflow {
+                        pc: self.current_pc,
+                    });
+                }
+                self.sp -= 1;
+                self.registers[register] = self.stack[self.sp];
+            }
+        }
+        Ok(false)
+    }
+
+    /// Moves the counter `displacement` bytes from the start of the current
+    /// instruction
+    fn relative_jump(&mut self, displacement: i16) -> Result<(), VmError> {
+        let target = self.current_pc as i64 + i64::from(displacement);
+        if target < 0 {
+            return Err(VmError::PcOutOfBounds {
+                pc: self.current_pc,
+                opcode: self.current_opcode,
+            });
+        }
+        self.counter = target as usize;
+        Ok(())
+    }
+
+    /// Updates the flags for an arithmetic result and picks the value to store
+    /// according to `arithmetic_mode`
+    fn arithmetic_result(
+        &mut self,
+        wrapped: i32,
+        saturated: i32,
+        carry: bool,
+        overflow: bool,
+    ) -> Result<i32, VmError> {
+        let result = match self.arithmetic_mode {
+            ArithmeticMode::Trap if overflow => {
+                return Err(VmError::ArithmeticOverflow {
+                    pc: self.current_pc,
+                    opcode: self.current_opcode.unwrap_or(Opcode::IGL),
+                });
+            }
+            ArithmeticMode::Saturate => saturated,
+            ArithmeticMode::Trap | ArithmeticMode::Wrap => wrapped,
+        };
+        self.set_flags(result, carry, overflow);
+        Ok(result)
+    }
+
+    /// Updates the zero and negative flags for the result of a bitwise or
+    /// shift opcode, which never carry or overflow
+    fn bitwise_result(&mut self, result: i32) -> i32 {
+        self.set_flags(result, false, false);
+        result
+    }
+
+    /// Sets the flags as if `register2` was subtracted from `register1`
+    fn compare(&mut self, register1: i32, register2: i32) {
+        let (difference, overflow) = register1.overflowing_sub(register2);
+        let carry = (register1 as u32) < (register2 as u32);
+        self.set_flags(difference, carry, overflow);
+    }
+
+    fn set_flags(&mut self, result: i32, carry: bool, overflow: bool) {
+        self.flags = 0;
+        if carry {
+            self.flags |= FLAG_CARRY;
+        }
+        if overflow {
+            self.flags |= FLAG_OVERFLOW;
+        }
+        if result == 0 {
+            self.flags |= FLAG_ZERO;
+        }
+        if result < 0 {
+            self.flags |= FLAG_NEGATIVE;
+        }
+    }
+
+    fn decode_opcode(&mut self) -> Result<Opcode, VmError> {
+        let opcode = Opcode::from(self.next_8_bits()?);
+        Ok(opcode)
+    }
+
+    fn next_8_bits(&mut self) -> Result<u8, VmError> {
+        match self.program.get(self.counter) {
+            Some(&result) => {
+                self.counter += 1;
+                Ok(result)
+            }
+            None => Err(VmError::PcOutOfBounds {
+                pc: self.counter,
+                opcode: self.current_opcode,
+            }),
+        }
+    }
+
+    fn next_16_bits(&mut self) -> Result<u16, VmError> {
+        let high = self.next_8_bits()? as u16;
+        let low = self.next_8_bits()? as u16;
+        Ok((high << 8) | low)
+    }
+
+    fn next_32_bits(&mut self) -> Result<u32, VmError> {
+        let high = u32::from(self.next_16_bits()?);
+        let low = u32::from(self.next_16_bits()?);
+        Ok((high << 16) | low)
+    }
+
+    /// Decodes a register operand, checking that it names one of the 32 registers
+    fn next_register(&mut self) -> Result<usize, VmError> {
+        let register = self.next_8_bits()?;
+        if register as usize >= self.registers.len() {
+            return Err(VmError::InvalidRegister {
+                pc: self.current_pc,
+                opcode: self.current_opcode.unwrap_or(Opcode::IGL),
+                register,
+            });
+        }
+        Ok(register as usize)
+    }
+
+    fn next_register_value(&mut self) -> Result<i32, VmError> {
+        let register = self.next_register()?;
+        Ok(self.registers[register])
+    }
+
+    /// Decodes a float register operand, checking that it names one of the 32
+    /// float registers
+    fn next_float_register(&mut self) -> Result<usize, VmError> {
+        let register = self.next_8_bits()?;
+        if register as usize >= self.float_registers.len() {
+            return Err(VmError::InvalidRegister {
+                pc: self.current_pc,
+                opcode: self.current_opcode.unwrap_or(Opcode::IGL),
+                register,
+            });
+        }
+        Ok(register as usize)
+    }
+
+    fn next_float_register_value(&mut self) -> Result<f64, VmError> {
+        let register = self.next_float_register()?;
+        Ok(self.float_registers[register])
+    }
+
+    /// Decodes the `$base #offset` operands of a heap access and checks that
+    /// `width` bytes starting there lie inside the heap
+    fn heap_address(&mut self, width: usize) -> Result<usize, VmError> {
+        let base = i64::from(self.next_register_value()?);
+        let address = base + i64::from(self.next_8_bits()?);
+        if address < 0 || address as usize + width > self.heap.len() {
+            return Err(VmError::HeapOutOfBounds {
+                pc: self.current_pc,
+                opcode: self.current_opcode.unwrap_or(Opcode::IGL),
+                address,
+                width,
+            });
+        }
+        Ok(address as usize)
+    }
+
+    pub fn add_byte(&mut self, b: u8) {
+        self.program.push(b);
+    }
+
+    pub fn add_bytes(&mut self, mut b: Vec<u8>) {
+        self.program.append(&mut b);
+    }
+
+    /// Parses the PIE header and copies the read-only section into
+    /// `ro_data`. Returns the entry point.
+    fn load_sections(&mut self) -> Result<usize, VmError> {
+        let header =
+            PieHeader::parse(&self.program).map_err(|error| VmError::BadHeader { error })?;
+        self.ro_data = self.program[header.ro_range()].to_vec();
+        Ok(header.entry_point as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::pie_header::{PieHeaderError, PIE_VERSION};
+
+    /// Wraps `code` in a PIE image with an empty read-only section
+    fn pie_program(code: &[u8]) -> Vec<u8> {
+        let mut program = PieHeader::new(0, code.len() as u32).to_bytes();
+        program.extend_from_slice(code);
+        program
+    }
+
+    fn get_test_vm() -> VM {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 10;
+        test_vm
+    }
+
+    #[test]
+    fn test_new_vm() {
+        let vm = VM::new();
+        assert_eq!(vm.registers[0], 0)
+    }
+
+    #[test]
+    fn test_opcode_hlt() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![6, 0, 0, 0];
+        test_vm.program = test_bytes;
+        assert_eq!(test_vm.run_once(), Ok(true));
+        assert_eq!(test_vm.counter, 1);
+    }
+
+    #[test]
+    fn test_opcode_igl() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![200, 0, 0, 0];
+        test_vm.program = test_bytes;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::IllegalOpcode { pc: 0, byte: 200 })
+        );
+        assert_eq!(test_vm.counter, 1);
+    }
+
+    #[test]
+    fn test_load_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![0, 0, 1, 244];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_add_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![1, 0, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 15);
+    }
+
+    #[test]
+    fn test_run_rejects_program_without_header() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![0, 0, 1, 244];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::BadHeader {
+                error: PieHeaderError::Truncated { length: 4 }
+            })
+        );
+        assert_eq!(test_vm.registers[0], 5);
+    }
+
+    #[test]
+    fn test_jmp_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 4;
+        test_vm.program = vec![7, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 4);
+    }
+
+    #[test]
+    fn test_call_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![18, 0, 8, 0, 6, 0, 0, 0, 19, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 8);
+        assert_eq!(
+            test_vm.call_stack,
+            vec![StackFrame {
+                return_address: 4,
+                frame_pointer: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_ret_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![18, 0, 8, 0, 6, 0, 0, 0, 19, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 4);
+        assert!(test_vm.call_stack.is_empty());
+    }
+
+    #[test]
+    fn test_ret_with_empty_call_stack() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![19, 0, 0, 0];
+        assert_eq!(
+            test_vm.execute_instruction(),
+            Err(VmError::CallStackUnderflow { pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_call_stack_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![18, 0, 0, 0];
+        for _ in 0..MAX_CALL_DEPTH {
+            assert_eq!(test_vm.execute_instruction(), Ok(false));
+        }
+        assert_eq!(
+            test_vm.execute_instruction(),
+            Err(VmError::CallStackOverflow { pc: 0 })
+        );
+        assert_eq!(test_vm.call_stack.len(), MAX_CALL_DEPTH);
+    }
+
+    #[test]
+    fn test_push_pop_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![20, 0, 0, 0, 20, 1, 0, 0, 21, 2, 0, 0, 21, 3, 0, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.sp, 2);
+        assert_eq!(&test_vm.stack[0..2], &[5, 10]);
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.sp, 0);
+        assert_eq!(test_vm.registers[2], 10);
+        assert_eq!(test_vm.registers[3], 5);
+        assert_eq!(test_vm.counter, 16);
+    }
+
+    #[test]
+    fn test_pop_underflow() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![21, 0, 0, 0];
+        assert_eq!(
+            test_vm.execute_instruction(),
+            Err(VmError::StackUnderflow { pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_push_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.sp = STACK_SIZE;
+        test_vm.program = vec![20, 0, 0, 0];
+        assert_eq!(
+            test_vm.execute_instruction(),
+            Err(VmError::StackOverflow { pc: 0 })
+        );
+        assert_eq!(test_vm.sp, STACK_SIZE);
+    }
+
+    #[test]
+    fn test_aloc_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![16, 0, 0, 0, 25, 1, 2, 4];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap.len(), 5);
+        assert_eq!(test_vm.counter, 4);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap[4], 10);
+    }
+
+    #[test]
+    fn test_aloc_rejects_negative_and_oversized_requests() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -5;
+        test_vm.program = vec![16, 0, 0, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidAllocation { pc: 0, bytes: -5 })
+        );
+        test_vm.counter = 0;
+        test_vm.registers[0] = i32::MAX;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidAllocation {
+                pc: 0,
+                bytes: i32::MAX
+            })
+        );
+        assert!(test_vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_heap_word_store_and_load() {
+        let mut test_vm = get_test_vm();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[0] = -2;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![27, 0, 1, 2, 24, 2, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(&test_vm.heap[4..8], &[255, 255, 255, 254]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], -2);
+    }
+
+    #[test]
+    fn test_heap_byte_and_halfword_access() {
+        let mut test_vm = get_test_vm();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[0] = 0x1234;
+        test_vm.registers[1] = 0;
+        test_vm.program = vec![26, 0, 1, 0, 25, 0, 1, 3, 23, 2, 1, 0, 22, 3, 1, 3];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap, vec![0x12, 0x34, 0, 0x34]);
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 0x1234);
+        assert_eq!(test_vm.registers[3], 0x34);
+    }
+
+    #[test]
+    fn test_heap_access_out_of_bounds() {
+        let mut test_vm = get_test_vm();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![24, 0, 1, 0];
+        assert_eq!(
+            test_vm.execute_instruction(),
+            Err(VmError::HeapOutOfBounds {
+                pc: 0,
+                opcode: Opcode::LOADMW,
+                address: 1,
+                width: 4
+            })
+        );
+        test_vm.registers[1] = -1;
+        test_vm.counter = 0;
+        test_vm.program = vec![25, 0, 1, 0];
+        assert_eq!(
+            test_vm.execute_instruction(),
+            Err(VmError::HeapOutOfBounds {
+                pc: 0,
+                opcode: Opcode::SETMB,
+                address: -1,
+                width: 1
+            })
+        );
+        assert_eq!(test_vm.heap, vec![0; 4]);
+    }
+
+    #[test]
+    fn test_ret_restores_frame() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![
+            20, 0, 0, 0, 18, 0, 12, 0, 6, 0, 0, 0, 20, 1, 0, 0, 19, 0, 0, 0,
+        ];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.fp, 1);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.sp, 2);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 8);
+        assert_eq!(test_vm.sp, 1);
+        assert_eq!(test_vm.fp, 0);
+    }
+
+    #[test]
+    fn test_div_by_zero() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 0;
+        test_vm.program = vec![4, 0, 1, 2];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::DivisionByZero {
+                pc: 0,
+                opcode: Opcode::DIV
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![6, 0, 0, 0, 1, 0, 32, 2];
+        test_vm.counter = 4;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidRegister {
+                pc: 4,
+                opcode: Opcode::ADD,
+                register: 32
+            })
+        );
+    }
+
+    #[test]
+    fn test_pc_out_of_bounds() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![0, 0, 1];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::PcOutOfBounds {
+                pc: 3,
+                opcode: Some(Opcode::LOAD)
+            })
+        );
+        test_vm.counter = 3;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::PcOutOfBounds {
+                pc: 3,
+                opcode: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_run_reports_crash() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = pie_program(&[200, 0, 0, 0]);
+        let error = test_vm.run().unwrap_err();
+        assert_eq!(error, VmError::IllegalOpcode { pc: 64, byte: 200 });
+        assert_eq!(error.pc(), Some(64));
+        match test_vm.events.last() {
+            Some(VMEvent {
+                event: VMEVentType::Crash { error: crashed },
+                ..
+            }) => assert_eq!(crashed, &error),
+            e => panic!("Expected a crash event, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_add_overflow_modes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![1, 0, 1, 2];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::ArithmeticOverflow {
+                pc: 0,
+                opcode: Opcode::ADD
+            })
+        );
+        assert_eq!(test_vm.registers[2], 0);
+
+        test_vm.arithmetic_mode = ArithmeticMode::Wrap;
+        test_vm.counter = 0;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert_eq!(test_vm.flags, FLAG_OVERFLOW | FLAG_NEGATIVE);
+
+        test_vm.arithmetic_mode = ArithmeticMode::Saturate;
+        test_vm.counter = 0;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MAX);
+        assert_eq!(test_vm.flags, FLAG_OVERFLOW);
+    }
+
+    #[test]
+    fn test_sub_sets_carry_and_zero() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![2, 0, 1, 2, 2, 0, 0, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], -5);
+        assert_eq!(test_vm.flags, FLAG_CARRY | FLAG_NEGATIVE);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 0);
+        assert_eq!(test_vm.flags, FLAG_ZERO);
+    }
+
+    #[test]
+    fn test_div_min_by_minus_one() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        test_vm.program = vec![4, 0, 1, 2];
+        test_vm.arithmetic_mode = ArithmeticMode::Wrap;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert_eq!(test_vm.remainder, 0);
+        test_vm.arithmetic_mode = ArithmeticMode::Saturate;
+        test_vm.counter = 0;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MAX);
+    }
+
+    #[test]
+    fn test_compare_sets_flags() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![12, 0, 1, 0, 10, 1, 0, 0, 8, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+        assert_eq!(test_vm.flags, FLAG_CARRY | FLAG_NEGATIVE);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.flags, 0);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.flags, FLAG_ZERO);
+    }
+
+    #[test]
+    fn test_flag_jumps() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[2] = 100;
+        test_vm.program = vec![28, 2, 0, 0, 30, 2, 0, 0];
+        test_vm.flags = FLAG_NEGATIVE;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 2);
+        test_vm.counter = 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 100);
+        test_vm.program = vec![29, 2, 0, 0];
+        test_vm.counter = 0;
+        test_vm.flags = FLAG_OVERFLOW;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 100);
+    }
+
+    #[test]
+    fn test_lui_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![0, 0, 0xEE, 0x90, 31, 0, 0xFF, 0xFE];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], -70000);
+    }
+
+    #[test]
+    fn test_and_or_xor_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        test_vm.program = vec![32, 0, 1, 2, 33, 0, 1, 3, 34, 0, 1, 4];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 0b1000);
+        assert_eq!(test_vm.registers[3], 0b1110);
+        assert_eq!(test_vm.registers[4], 0b0110);
+    }
+
+    #[test]
+    fn test_not_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![35, 0, 2, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], -6);
+        assert_eq!(test_vm.flags, FLAG_NEGATIVE);
+        assert_eq!(test_vm.counter, 4);
+    }
+
+    #[test]
+    fn test_shl_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 3;
+        test_vm.program = vec![36, 0, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 40);
+    }
+
+    #[test]
+    fn test_shr_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -16;
+        test_vm.registers[1] = 28;
+        test_vm.program = vec![37, 0, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 0xF);
+    }
+
+    #[test]
+    fn test_sar_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -16;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![38, 0, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], -4);
+    }
+
+    #[test]
+    fn test_loadf_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![39, 2, 0, 0];
+        test_vm
+            .program
+            .extend_from_slice(&(-2.25f64).to_bits().to_be_bytes());
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.float_registers[2], -2.25);
+        assert_eq!(test_vm.counter, 12);
+    }
+
+    #[test]
+    fn test_float_arithmetic_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 7.5;
+        test_vm.float_registers[1] = 2.5;
+        test_vm.program = vec![40, 0, 1, 2, 41, 0, 1, 3, 42, 0, 1, 4, 43, 0, 1, 5];
+        for _ in 0..4 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(test_vm.float_registers[2], 10.0);
+        assert_eq!(test_vm.float_registers[3], 5.0);
+        assert_eq!(test_vm.float_registers[4], 18.75);
+        assert_eq!(test_vm.float_registers[5], 3.0);
+    }
+
+    #[test]
+    fn test_float_compare_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 1.0;
+        test_vm.float_registers[1] = 2.0;
+        test_vm.program = vec![48, 0, 1, 0, 46, 0, 1, 0, 44, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_int_float_conversion() {
+        let mut test_vm = get_test_vm();
+        test_vm.float_registers[1] = -3.75;
+        test_vm.program = vec![50, 1, 0, 0, 51, 1, 2, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.float_registers[0], 10.0);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], -3);
+    }
+
+    #[test]
+    fn test_jmpf_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![6, 0, 0, 0, 52, 0, 8, 0];
+        test_vm.counter = 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 12);
+    }
+
+    #[test]
+    fn test_jmpb_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![6, 0, 0, 0, 53, 0, 4, 0, 53, 0, 16, 0];
+        test_vm.counter = 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 0);
+        test_vm.counter = 8;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::PcOutOfBounds {
+                pc: 8,
+                opcode: Some(Opcode::JMPB)
+            })
+        );
+    }
+
+    #[test]
+    fn test_jneq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![6, 0, 0, 0, 54, 0xFF, 0xFC, 0];
+        test_vm.counter = 4;
+        test_vm.equal_flag = true;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 8);
+        test_vm.counter = 4;
+        test_vm.equal_flag = false;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 0);
+    }
+
+    #[test]
+    fn test_jz_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![55, 0, 12, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 4);
+        test_vm.counter = 0;
+        test_vm.flags = FLAG_ZERO;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.counter, 12);
+    }
+
+    #[test]
+    fn test_run_loads_ro_section() {
+        let mut asm = crate::assembler::Assembler::new();
+        let program = asm
+            .assemble(".data\nhello: .asciiz 'Hello'\n.code\nprts @hello\nhlt\n")
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(program);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.ro_data, b"Hello\0".to_vec());
+    }
+
+    #[test]
+    fn test_ro_section_longer_than_program() {
+        let mut test_vm = VM::new();
+        test_vm.program = PieHeader::new(10, 4).to_bytes();
+        test_vm.program.extend_from_slice(&[6, 0, 0, 0]);
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::BadHeader {
+                error: PieHeaderError::SectionOutOfBounds {
+                    section: "read-only"
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_unsupported_pie_version() {
+        let mut test_vm = VM::new();
+        let mut header = PieHeader::new(0, 4);
+        header.version = PIE_VERSION + 1;
+        test_vm.program = header.to_bytes();
+        test_vm.program.extend_from_slice(&[6, 0, 0, 0]);
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::BadHeader {
+                error: PieHeaderError::UnsupportedVersion {
+                    version: PIE_VERSION + 1
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_run_starts_at_entry_point() {
+        let mut test_vm = VM::new();
+        // load $0 #1; hlt; load $0 #2; hlt
+        let mut header = PieHeader::new(0, 16);
+        header.entry_point += 8;
+        test_vm.program = header.to_bytes();
+        test_vm
+            .program
+            .extend_from_slice(&[0, 0, 0, 1, 6, 0, 0, 0, 0, 0, 0, 2, 6, 0, 0, 0]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 2);
+    }
+
+    #[test]
+    fn test_prts_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.ro_data = b"Hi".to_vec();
+        test_vm.program = vec![17, 0, 0, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::RoDataOutOfBounds { pc: 0, offset: 0 })
+        );
+        test_vm.ro_data = b"Hi\0".to_vec();
+        test_vm.counter = 0;
+        test_vm.program = vec![17, 0, 9, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::RoDataOutOfBounds { pc: 0, offset: 9 })
+        );
+    }
+
+    fn get_looping_program() -> Vec<u8> {
+        // load $0 #0; load $1 #1; add $0 $1 $0; jmpb #4
+        pie_program(&[0, 0, 0, 0, 0, 1, 0, 1, 1, 0, 1, 0, 53, 0, 4, 0])
+    }
+
+    #[test]
+    fn test_run_out_of_fuel() {
+        let mut test_vm = VM::new();
+        test_vm.program = get_looping_program();
+        test_vm.fuel = Some(10);
+        assert_eq!(test_vm.run(), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.fuel, Some(0));
+        assert_eq!(test_vm.registers[0], 4);
+        assert_eq!(test_vm.counter, 72);
+    }
+
+    #[test]
+    fn test_resume_after_refuel() {
+        let mut test_vm = VM::new();
+        test_vm.program = get_looping_program();
+        test_vm.fuel = Some(10);
+        test_vm.run().unwrap();
+        test_vm.fuel = Some(2);
+        assert_eq!(test_vm.resume(), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.registers[0], 5);
+    }
+
+    #[test]
+    fn test_run_until_halt_with_fuel() {
+        let mut test_vm = VM::new();
+        test_vm.program = pie_program(&[0, 0, 0, 7, 6, 0, 0, 0]);
+        test_vm.fuel = Some(5);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.fuel, Some(3));
+        assert_eq!(test_vm.registers[0], 7);
+    }
+
+    #[test]
+    fn test_interrupt_before_run() {
+        let mut test_vm = VM::new();
+        test_vm.program = get_looping_program();
+        let handle = test_vm.interrupt_handle();
+        handle.interrupt();
+        assert!(handle.is_interrupted());
+        assert_eq!(test_vm.run(), Ok(ExitReason::Interrupted));
+        assert_eq!(test_vm.counter, 64);
+        assert!(!handle.is_interrupted());
+    }
+
+    #[test]
+    fn test_interrupt_from_another_thread() {
+        let mut test_vm = VM::new();
+        test_vm.program = get_looping_program();
+        let handle = test_vm.interrupt_handle();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            handle.interrupt();
+        });
+        assert_eq!(test_vm.run(), Ok(ExitReason::Interrupted));
+        interrupter.join().unwrap();
+        let before = test_vm.registers[0];
+        test_vm.fuel = Some(2);
+        assert_eq!(test_vm.resume(), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.registers[0], before + 1);
+    }
+}
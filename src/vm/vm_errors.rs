@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::assembler::pie_header::PieHeaderError;
+use crate::instruction::Opcode;
+use crate::vm::HEAP_LIMIT;
+
+/// Faults raised while executing a program. `pc` is the address of the
+/// instruction that faulted, except for `PcOutOfBounds` where it is the
+/// counter value that fell outside the program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    IllegalOpcode {
+        pc: usize,
+        byte: u8,
+    },
+    DivisionByZero {
+        pc: usize,
+        opcode: Opcode,
+    },
+    ArithmeticOverflow {
+        pc: usize,
+        opcode: Opcode,
+    },
+    InvalidRegister {
+        pc: usize,
+        opcode: Opcode,
+        register: u8,
+    },
+    PcOutOfBounds {
+        pc: usize,
+        opcode: Option<Opcode>,
+    },
+    BadHeader {
+        error: PieHeaderError,
+    },
+    HeapOutOfBounds {
+        pc: usize,
+        opcode: Opcode,
+        address: i64,
+        width: usize,
+    },
+    InvalidAllocation {
+        pc: usize,
+        bytes: i32,
+    },
+    CallStackOverflow {
+        pc: usize,
+    },
+    CallStackUnderflow {
+        pc: usize,
+    },
+    StackOverflow {
+        pc: usize,
+    },
+    StackUnderflow {
+        pc: usize,
+    },
+    RoDataOutOfBounds {
+        pc: usize,
+        offset: usize,
+    },
+}
+
+impl VmError {
+    /// Program counter the fault was raised at, if it happened while executing
+    pub fn pc(&self) -> Option<usize> {
+        match *self {
+            VmError::IllegalOpcode { pc, .. }
+            | VmError::DivisionByZero { pc, .. }
+            | VmError::ArithmeticOverflow { pc, .. }
+            | VmError::InvalidRegister { pc, .. }
+            | VmError::PcOutOfBounds { pc, .. }
+            | VmError::HeapOutOfBounds { pc, .. }
+            | VmError::InvalidAllocation { pc, .. }
+            | VmError::CallStackOverflow { pc }
+            | VmError::CallStackUnderflow { pc }
+            | VmError::StackOverflow { pc }
+            | VmError::StackUnderflow { pc }
+            | VmError::RoDataOutOfBounds { pc, .. } => Some(pc),
+            VmError::BadHeader { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VmError::IllegalOpcode { pc, byte } => {
+                write!(f, "Illegal opcode {} at {}", byte, pc)
+            }
+            VmError::DivisionByZero { pc, opcode } => {
+                write!(f, "Division by zero in {:?} at {}", opcode, pc)
+            }
+            VmError::ArithmeticOverflow { pc, opcode } => {
+                write!(f, "Arithmetic overflow in {:?} at {}", opcode, pc)
+            }
+            VmError::InvalidRegister {
+                pc,
+                opcode,
+                register,
+            } => write!(
+                f,
+                "Invalid register ${} used by {:?} at {}",
+                register, opcode, pc
+            ),
+            VmError::PcOutOfBounds {
+                pc,
+                opcode: Some(opcode),
+            } => write!(
+                f,
+                "Program counter {} is out of bounds while decoding {:?}",
+                pc, opcode
+            ),
+            VmError::PcOutOfBounds { pc, opcode: None } => {
+                write!(f, "Program counter {} is out of bounds", pc)
+            }
+            VmError::BadHeader { ref error } => write!(f, "Invalid PIE header: {}", error),
+            VmError::HeapOutOfBounds {
+                pc,
+                opcode,
+                address,
+                width,
+            } => write!(
+                f,
+                "{:?} at {} accessed {} bytes at heap address {}, which is out of bounds",
+                opcode, pc, width, address
+            ),
+            VmError::InvalidAllocation { pc, bytes } => write!(
+                f,
+                "ALOC at {} requested {} bytes, which is negative or grows the heap past {} bytes",
+                pc, bytes, HEAP_LIMIT
+            ),
+            VmError::CallStackOverflow { pc } => write!(f, "Call stack overflow at {}", pc),
+            VmError::CallStackUnderflow { pc } => {
+                write!(f, "Return with an empty call stack at {}", pc)
+            }
+            VmError::StackOverflow { pc } => write!(f, "Stack overflow at {}", pc),
+            VmError::StackUnderflow { pc } => write!(f, "Stack underflow at {}", pc),
+            VmError::RoDataOutOfBounds { pc, offset } => write!(
+                f,
+                "No terminated string at read-only offset {} (at {})",
+                offset, pc
+            ),
+        }
+    }
+}
+
+impl Error for VmError {
+    fn description(&self) -> &str {
+        match self {
+            VmError::IllegalOpcode { .. } => "Illegal opcode",
+            VmError::DivisionByZero { .. } => "Division by zero",
+            VmError::ArithmeticOverflow { .. } => "Arithmetic overflow",
+            VmError::InvalidRegister { .. } => "Invalid register",
+            VmError::PcOutOfBounds { .. } => "Program counter out of bounds",
+            VmError::BadHeader { .. } => "Invalid PIE header",
+            VmError::HeapOutOfBounds { .. } => "Heap access out of bounds",
+            VmError::InvalidAllocation { .. } => "Invalid heap allocation",
+            VmError::CallStackOverflow { .. } => "Call stack overflow",
+            VmError::CallStackUnderflow { .. } => "Return with an empty call stack",
+            VmError::StackOverflow { .. } => "Stack overflow",
+            VmError::StackUnderflow { .. } => "Stack underflow",
+            VmError::RoDataOutOfBounds { .. } => "Read-only data access out of bounds",
+        }
+    }
+}